@@ -0,0 +1,4967 @@
+//! A library to format JSONC (JSON with Comments) text.
+//!
+//! A `/* jcfmt:expand */` or `/* jcfmt:collapse */` comment just before an array or object
+//! writes it on multiple lines or on a single line, overriding the layout that the input and
+//! the [`FormatOptions`] would give (a container with comments inside is never collapsed).
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::num::NonZeroUsize;
+use std::ops::{Range, RangeInclusive};
+
+const INDENT_SIZE: usize = 2;
+
+/// Formatting options.
+#[derive(Debug, Default, Clone)]
+pub struct FormatOptions {
+    /// Remove all comments (including a leading `#!` line) and trailing commas.
+    pub strip: bool,
+
+    /// When stripping, replace each removed comment line with an empty line.
+    pub strip_comments_to_blank_lines: bool,
+
+    /// Keep the comments that start with one of these prefixes (e.g., `//#` for
+    /// `//# sourceMappingURL=...`) as they are, even when stripping or rewriting comments.
+    ///
+    /// Only their trailing whitespace is removed.
+    pub preserved_comment_prefixes: Vec<String>,
+
+    /// Align the trailing comments of multiline object members to a common column.
+    pub comment_align_to_value: bool,
+
+    /// Keep the input's trailing newlines as-is, adding one only if none are present.
+    pub trailing_newline_if_missing_only: bool,
+
+    /// Indentation columns for nesting levels 1, 2, ... (deeper levels repeat the last step).
+    ///
+    /// The columns should be in non-decreasing order; a decreasing last step is treated as zero.
+    pub indent_spec: Option<Vec<usize>>,
+
+    /// Expand arrays and objects whose single-line form would exceed this width.
+    ///
+    /// A line whose width is exactly this value still fits (i.e., stays on a single line).
+    pub max_width: Option<usize>,
+
+    /// Exclude trailing comments when measuring lines for [`FormatOptions::max_width`].
+    pub max_width_ignore_comments: bool,
+
+    /// Rewrite trailing `//` comments in the given style.
+    pub trailing_comment_style: Option<TrailingCommentStyle>,
+
+    /// Sort object members by key in the given order.
+    pub sort_keys: Option<KeyOrder>,
+
+    /// Only sort the objects at these JSON Pointers (e.g., `/foo/bar`) when
+    /// [`sort_keys`](Self::sort_keys) is set, leaving the others in source order.
+    pub sort_keys_at: Option<Vec<String>>,
+
+    /// Keep standalone comments in their original order instead of moving them with sorted members.
+    pub sort_keys_keep_comments_fixed: bool,
+
+    /// Also accept `#` line comments (in addition to `//` and `/* */`), which are kept as-is.
+    pub allow_hash_comments: bool,
+
+    /// Accept input with no JSON value, only comments (e.g., a file being scaffolded), and
+    /// format just the comments.
+    pub allow_comment_only: bool,
+
+    /// Accept arbitrary content after the JSON value (and its trailing comments), instead of
+    /// reporting a parse error, and handle it as specified.
+    pub trailing_content: Option<TrailingContent>,
+
+    /// Replace the `//` comments before object members with `"<key>__comment"` members
+    /// holding the comment text, for tools that don't support comments.
+    pub comments_to_fields: bool,
+
+    /// Re-encode strings with minimal escapes: short escapes such as `\n` and `\"` where
+    /// available, `\u00XX` for the other control characters, and literal characters otherwise.
+    pub normalize_escapes: bool,
+
+    /// Rewrite numbers in the given format.
+    pub normalize_numbers: Option<NumberFormat>,
+
+    /// Write numbers as strings (e.g., `123` becomes `"123"`), for consumers that would
+    /// otherwise lose precision.
+    pub numbers_as_strings: bool,
+
+    /// Write these kinds of empty values as `null`.
+    ///
+    /// Empty arrays and objects that contain comments are kept as they are.
+    pub empty_to_null: EmptyValues,
+
+    /// Write arrays and objects that span multiple lines in the input (and contain no
+    /// comments) on a single line if that line would be at most this wide.
+    ///
+    /// Unlike [`max_width`](Self::max_width), this never expands single-line containers.
+    pub collapse_width: Option<usize>,
+
+    /// Put each element of arrays with at least this many elements on its own line, and
+    /// write smaller arrays on a single line even if they span multiple lines in the input
+    /// (as long as they contain no comments and fit within [`max_width`](Self::max_width)).
+    pub array_rows_threshold: Option<usize>,
+
+    /// Put each member of objects with at least this many members on its own line, and
+    /// write smaller objects on a single line even if they span multiple lines in the input
+    /// (as long as they contain no comments and fit within [`max_width`](Self::max_width)).
+    pub object_rows_threshold: Option<usize>,
+
+    /// Write the closing brackets of nested multiline containers that close together on a
+    /// single line (e.g., `]]]`), instead of each on its own line.
+    pub hug_closing_brackets: bool,
+
+    /// Indentation of the closing brackets of multiline arrays and objects.
+    pub closing_bracket_indent: ClosingBracketIndent,
+
+    /// Only show the first N elements of each array, followed by a comment with the number of
+    /// omitted elements.
+    ///
+    /// This is meant for previewing large documents; the output no longer has the same content.
+    pub preview_array_limit: Option<usize>,
+
+    /// Append a comment after the root value with the numbers of objects, arrays and other
+    /// values in it (e.g., `/* 1 object, 2 arrays, 5 values */`).
+    pub append_stats: bool,
+
+    /// Only reformat the object members and array elements whose first or last line is within
+    /// these (1-based, inclusive) line ranges, including the indentation of their lines.
+    ///
+    /// A member spanning several lines is reformatted as a whole even if only one of its lines
+    /// changed, and a changed line with its closing bracket reformats the whole container.
+    pub changed_lines: Option<Vec<RangeInclusive<usize>>>,
+}
+
+/// Key order used by [`FormatOptions::sort_keys`].
+///
+/// Keys are compared after unescaping (so `"\u0061"` and `"a"` are equal), and members with
+/// equal keys keep their relative order. The orders depend only on the keys, never on the
+/// system locale or other environment settings, so the output is the same on every machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyOrder {
+    /// Lexical order of the Unicode code points of the keys (the same as the order of their
+    /// UTF-8 bytes).
+    ///
+    /// There is no case folding or collation: `"B"` comes before `"a"`, and `"z"` before `"é"`.
+    /// This differs from JavaScript's UTF-16 order for keys beyond U+FFFF.
+    Lexical,
+
+    /// Keys consisting only of ASCII digits first, in numeric order (ignoring leading zeros,
+    /// with ties broken lexically, e.g., `"2"`, `"010"`, `"10"`), then the others in
+    /// [`Lexical`](Self::Lexical) order.
+    NumericFirst,
+}
+
+impl KeyOrder {
+    fn compare(self, a: &str, b: &str) -> std::cmp::Ordering {
+        let numeric = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        match self {
+            KeyOrder::Lexical => a.cmp(b),
+            KeyOrder::NumericFirst => match (numeric(a), numeric(b)) {
+                (true, true) => {
+                    let (a_digits, b_digits) =
+                        (a.trim_start_matches('0'), b.trim_start_matches('0'));
+                    a_digits
+                        .len()
+                        .cmp(&b_digits.len())
+                        .then_with(|| a_digits.cmp(b_digits))
+                        .then_with(|| a.cmp(b))
+                }
+                (true, false) => std::cmp::Ordering::Less,
+                (false, true) => std::cmp::Ordering::Greater,
+                (false, false) => a.cmp(b),
+            },
+        }
+    }
+}
+
+/// Indentation of closing brackets, used by [`FormatOptions::closing_bracket_indent`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClosingBracketIndent {
+    /// Same as the line of the opening bracket.
+    #[default]
+    MatchOpen,
+
+    /// Same as the elements or members (one level deeper than the opening bracket's line).
+    MatchContent,
+}
+
+/// Style of trailing comments used by [`FormatOptions::trailing_comment_style`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingCommentStyle {
+    /// Keep `//` comments where they fit, but convert one followed by a comma into a
+    /// `/* */` comment so that the comma stays on the same line, and move one that would exceed
+    /// [`FormatOptions::max_width`] (even without alignment padding) to the next line.
+    Auto,
+}
+
+/// Kinds of empty values, used by [`FormatOptions::empty_to_null`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EmptyValues {
+    /// `""` (object keys are never converted).
+    pub strings: bool,
+
+    /// `[]`
+    pub arrays: bool,
+
+    /// `{}`
+    pub objects: bool,
+}
+
+/// Number format used by [`FormatOptions::normalize_numbers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// The format of ECMAScript's `Number.prototype.toString()` (and `JSON.stringify()`):
+    /// the shortest representation that round-trips through an IEEE 754 double.
+    Js,
+}
+
+/// What to do with non-comment content after the JSON value, used by
+/// [`FormatOptions::trailing_content`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingContent {
+    /// Copy it verbatim after the formatted value.
+    Preserve,
+
+    /// Remove it.
+    Drop,
+}
+
+impl FormatOptions {
+    fn indent_width(&self, level: usize) -> usize {
+        let Some(columns) = &self.indent_spec else {
+            return level * INDENT_SIZE;
+        };
+        match (level, columns.as_slice()) {
+            (0, _) | (_, []) => 0,
+            (_, _) if level <= columns.len() => columns[level - 1],
+            (_, [.., prev, last]) => last + (level - columns.len()) * last.saturating_sub(*prev),
+            (_, [last]) => last * level,
+        }
+    }
+}
+
+/// Error returned when formatting fails.
+#[derive(Debug)]
+pub enum FormatError {
+    /// The input is not valid UTF-8.
+    InvalidUtf8(std::str::Utf8Error),
+
+    /// The input is not valid JSONC (the message includes the offending input line).
+    Parse(String),
+
+    /// Writing the output failed (the message includes the input line where it failed).
+    Fmt(String),
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::InvalidUtf8(e) => write!(f, "input is not valid UTF-8: {e}"),
+            FormatError::Parse(message) => write!(f, "{message}"),
+            FormatError::Fmt(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// Non-fatal issue found while formatting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// An object has more than one member with this key.
+    DuplicateKey {
+        /// Unescaped key.
+        key: String,
+        /// 1-based input line of the duplicate occurrence.
+        line: usize,
+    },
+
+    /// An output line is wider than [`FormatOptions::max_width`].
+    LongLine {
+        /// 1-based output line.
+        line: usize,
+        /// Width of the line in characters.
+        width: usize,
+    },
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::DuplicateKey { key, line } => {
+                write!(f, "duplicate key {key:?} at line {line}")
+            }
+            Warning::LongLine { line, width } => {
+                let plural = if *width == 1 { "" } else { "s" };
+                write!(f, "output line {line} is {width} character{plural} wide")
+            }
+        }
+    }
+}
+
+/// Result of [`format_detailed()`].
+#[derive(Debug, Clone)]
+pub struct FormatResult {
+    /// Formatted text.
+    pub formatted: String,
+
+    /// Whether the formatted text differs from the input.
+    pub changed: bool,
+
+    /// Non-fatal issues found in the input or the output.
+    pub warnings: Vec<Warning>,
+
+    /// Time taken to parse and format the input.
+    pub elapsed: std::time::Duration,
+}
+
+/// Formats JSONC text given as bytes, which must be valid UTF-8.
+pub fn format_bytes(input: &[u8], options: &FormatOptions) -> Result<Vec<u8>, FormatError> {
+    let text = std::str::from_utf8(input).map_err(FormatError::InvalidUtf8)?;
+    format_text(text, options).map(String::into_bytes)
+}
+
+/// Formats JSONC text.
+///
+/// A `#!` line at the start of the text is kept as the first line of the output, unless
+/// [`FormatOptions::strip`] is set: it is removed like the comments then, so that the output is
+/// strict JSON.
+pub fn format_text(text: &str, options: &FormatOptions) -> Result<String, FormatError> {
+    format_detailed(text, options).map(|result| result.formatted)
+}
+
+/// Formats a stream of newline-delimited JSONC documents (e.g., NDJSON), one per non-blank line.
+///
+/// Each document is formatted independently, so a document that fails to format yields an
+/// error without stopping the iteration. The error positions are relative to the document's line.
+pub fn format_stream<'a>(
+    text: &'a str,
+    options: &'a FormatOptions,
+) -> impl Iterator<Item = Result<String, FormatError>> + 'a {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(move |line| format_text(line, options))
+}
+
+/// Formats JSONC text and also reports whether it changed and any [`Warning`]s.
+pub fn format_detailed(text: &str, options: &FormatOptions) -> Result<FormatResult, FormatError> {
+    let start = std::time::Instant::now();
+    let (masked, hash_comment_ranges) = mask_hash_comments(text, options.allow_hash_comments);
+    let (json, mut comment_ranges) = match nojson::RawJson::parse_jsonc(&masked) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            if let (nojson::JsonParseError::UnexpectedTrailingChar { position, .. }, Some(policy)) =
+                (&e, options.trailing_content)
+            {
+                let mut result = format_detailed(&text[..*position], options)?;
+                if policy == TrailingContent::Preserve {
+                    result.formatted.push_str(&text[*position..]);
+                }
+                result.changed = result.formatted != text;
+                result.elapsed = start.elapsed();
+                return Ok(result);
+            }
+            let error = FormatError::Parse(format_json_parse_error(text, e));
+            let formatted =
+                format_comment_only(text, &masked, hash_comment_ranges, options).ok_or(error)??;
+            return Ok(FormatResult {
+                changed: formatted != text,
+                formatted,
+                warnings: Vec::new(),
+                elapsed: start.elapsed(),
+            });
+        }
+    };
+    comment_ranges.extend(hash_comment_ranges);
+    let formatted = format_parsed(text, &json, comment_ranges, options)?;
+
+    let mut warnings = Vec::new();
+    collect_duplicate_keys(text, json.value(), &mut warnings);
+    if let Some(max_width) = options.max_width {
+        for (i, line) in formatted.lines().enumerate() {
+            let width = line.chars().count();
+            if width > max_width {
+                warnings.push(Warning::LongLine { line: i + 1, width });
+            }
+        }
+    }
+
+    Ok(FormatResult {
+        changed: formatted != text,
+        formatted,
+        warnings,
+        elapsed: start.elapsed(),
+    })
+}
+
+// Blanks out a leading shebang line (and `#` line comments, if allowed) so that nojson can
+// parse the text, and returns their ranges so that they are formatted as line comments.
+//
+// Positions don't shift, so the ranges and the parsed values also apply to the original text.
+fn mask_hash_comments(text: &str, allow_hash_comments: bool) -> (Cow<'_, str>, Vec<Range<usize>>) {
+    let line_end = |start: usize| text[start..].find('\n').map_or(text.len(), |i| start + i);
+
+    let mut ranges = Vec::new();
+    let mut position = 0;
+    if text.starts_with("#!") {
+        position = line_end(0);
+        ranges.push(0..position);
+    }
+    if allow_hash_comments {
+        let bytes = text.as_bytes();
+        while position < bytes.len() {
+            match (bytes[position], bytes.get(position + 1)) {
+                (b'"', _) => {
+                    position += 1;
+                    while position < bytes.len() && bytes[position] != b'"' {
+                        position += if bytes[position] == b'\\' { 2 } else { 1 };
+                    }
+                    position += 1;
+                }
+                (b'/', Some(b'/')) => position = line_end(position),
+                (b'/', Some(b'*')) => {
+                    position = text[position + 2..]
+                        .find("*/")
+                        .map_or(text.len(), |i| position + 2 + i + 2);
+                }
+                (b'#', _) => {
+                    let end = line_end(position);
+                    ranges.push(position..end);
+                    position = end;
+                }
+                _ => position += 1,
+            }
+        }
+    }
+
+    if ranges.is_empty() {
+        return (Cow::Borrowed(text), ranges);
+    }
+    let mut masked = text.to_owned();
+    for range in &ranges {
+        masked.replace_range(range.clone(), &" ".repeat(range.len()));
+    }
+    (Cow::Owned(masked), ranges)
+}
+
+fn collect_duplicate_keys(
+    text: &str,
+    value: nojson::RawJsonValue<'_, '_>,
+    warnings: &mut Vec<Warning>,
+) {
+    match value.kind() {
+        nojson::JsonValueKind::Array => {
+            for element in value.to_array().expect("bug") {
+                collect_duplicate_keys(text, element, warnings);
+            }
+        }
+        nojson::JsonValueKind::Object => {
+            let mut keys = std::collections::BTreeSet::new();
+            for (key, value) in value.to_object().expect("bug") {
+                let unescaped = key.to_unquoted_string_str().expect("bug");
+                if !keys.insert(unescaped.clone()) {
+                    warnings.push(Warning::DuplicateKey {
+                        key: unescaped.into_owned(),
+                        line: text[..key.position()].matches('\n').count() + 1,
+                    });
+                }
+                collect_duplicate_keys(text, value, warnings);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn format_parsed(
+    text: &str,
+    json: &nojson::RawJson<'_>,
+    comment_ranges: Vec<Range<usize>>,
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    if let Some(lines) = &options.changed_lines {
+        let mut output = String::new();
+        format_changed_values(text, json, comment_ranges, &mut output, options, lines)?;
+        return Ok(output);
+    }
+    run_formatter(text, comment_ranges, options, |formatter| {
+        formatter.format(json.value())
+    })
+}
+
+// Runs `format` on a new formatter and returns its output, with CRLF line endings if the text
+// uses them.
+fn run_formatter(
+    text: &str,
+    comment_ranges: Vec<Range<usize>>,
+    options: &FormatOptions,
+    format: impl FnOnce(&mut Formatter<'_, &mut String>) -> std::fmt::Result,
+) -> Result<String, FormatError> {
+    let mut output = String::new();
+    let mut formatter = Formatter::with_options(text, comment_ranges, &mut output, options);
+    format(&mut formatter).map_err(|e| format_failure(text, formatter.text_position, e))?;
+    if uses_crlf(text) {
+        output = output.replace('\n', "\r\n");
+    }
+    Ok(output)
+}
+
+// Formats a text that contains nothing but comments and whitespace, if allowed.
+fn format_comment_only(
+    text: &str,
+    masked: &str,
+    hash_comment_ranges: Vec<Range<usize>>,
+    options: &FormatOptions,
+) -> Option<Result<String, FormatError>> {
+    if !options.allow_comment_only {
+        return None;
+    }
+    let mut comment_ranges = parse_comment_only(masked)?;
+    comment_ranges.extend(hash_comment_ranges);
+    Some(run_formatter(text, comment_ranges, options, |formatter| {
+        formatter.format_comments_only()
+    }))
+}
+
+// Returns the comment ranges if the text consists of only comments and whitespace.
+fn parse_comment_only(masked: &str) -> Option<Vec<Range<usize>>> {
+    let with_value = format!("{masked}\nnull");
+    let (json, comment_ranges) = nojson::RawJson::parse_jsonc(&with_value).ok()?;
+    (json.value().position() == masked.len() + 1).then_some(comment_ranges)
+}
+
+fn format_changed_values(
+    text: &str,
+    json: &nojson::RawJson<'_>,
+    comment_ranges: Vec<Range<usize>>,
+    output: &mut String,
+    options: &FormatOptions,
+    lines: &[RangeInclusive<usize>],
+) -> Result<(), FormatError> {
+    let line_starts = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    let line_of = |position: usize| line_starts.partition_point(|&start| start <= position);
+
+    let is_changed = |line: usize| lines.iter().any(|r| r.contains(&line));
+
+    // An object member (or array element) overlapping the changed lines is reformatted as a
+    // whole if its first or last line is changed. Otherwise only the changed lines inside it
+    // are, so its children on them are visited instead.
+    let mut targets = Vec::new();
+    let mut stack = vec![(None, json.value(), Vec::new())];
+    while let Some((key, value, path)) = stack.pop() {
+        let start = fragment_start(key, value);
+        let end = value.position() + value.as_raw_str().len();
+        let (start_line, end_line) = (line_of(start), line_of(end - 1));
+        if !lines
+            .iter()
+            .any(|r| *r.start() <= end_line && start_line <= *r.end())
+        {
+            continue;
+        }
+        if is_changed(start_line) || is_changed(end_line) {
+            targets.push((key, value, path));
+            continue;
+        }
+        match value.kind() {
+            nojson::JsonValueKind::Array => {
+                stack.extend(value.to_array().expect("bug").enumerate().map(|(i, v)| {
+                    let mut path = path.clone();
+                    path.push(PathSegment::Index(i));
+                    (None, v, path)
+                }));
+            }
+            nojson::JsonValueKind::Object => {
+                stack.extend(value.to_object().expect("bug").map(|(k, v)| {
+                    let mut path = path.clone();
+                    path.push(PathSegment::key(k));
+                    (Some(k), v, path)
+                }));
+            }
+            _ => {}
+        }
+    }
+    targets.sort_by_key(|&(key, value, _)| fragment_start(key, value));
+
+    if targets
+        .first()
+        .is_some_and(|(key, value, _)| key.is_none() && value.position() == json.value().position())
+    {
+        *output = run_formatter(text, comment_ranges, options, |formatter| {
+            formatter.format(json.value())
+        })?;
+        return Ok(());
+    }
+
+    let mut position = 0;
+    for (key, value, path) in targets {
+        let start = fragment_start(key, value);
+        let end = value.position() + value.as_raw_str().len();
+
+        // The indentation is rewritten too if nothing else precedes the fragment on its line.
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let at_line_start = text[line_start..start].trim().is_empty();
+        output.push_str(&text[position..if at_line_start { line_start } else { start }]);
+        let comment_ranges = comment_ranges
+            .iter()
+            .filter(|r| start <= r.start && r.end <= end)
+            .cloned()
+            .collect();
+        // The text between the fragments is copied as-is, so only the fragments get CRLF.
+        let fragment = run_formatter(text, comment_ranges, options, |formatter| {
+            formatter.format_fragment(key, value, path, at_line_start)
+        })?;
+        output.push_str(&fragment);
+        position = end;
+    }
+    output.push_str(&text[position..]);
+    Ok(())
+}
+
+// Returns the start of an object member (its key) or array element.
+fn fragment_start(
+    key: Option<nojson::RawJsonValue<'_, '_>>,
+    value: nojson::RawJsonValue<'_, '_>,
+) -> usize {
+    key.map_or(value.position(), |key| key.position())
+}
+
+fn uses_crlf(text: &str) -> bool {
+    text.find('\n').is_some_and(|i| text[..i].ends_with('\r'))
+}
+
+/// Returns the hex-encoded SHA-256 hash of the canonical form of a JSONC text.
+///
+/// The canonical form ignores comments and whitespace: it is the compact JSON text with
+/// keys sorted, strings re-encoded with minimal escapes and numbers written as in
+/// [`NumberFormat::Js`] (similar to RFC 8785). So two texts that differ only in formatting
+/// have the same hash.
+///
+/// The text is parsed as in [`format_detailed()`]: with [`FormatOptions::trailing_content`],
+/// only the value before the trailing content is hashed, and with
+/// [`FormatOptions::allow_comment_only`], a text without a value has the canonical form `""`.
+pub fn canonical_hash(text: &str, options: &FormatOptions) -> Result<String, FormatError> {
+    let (masked, _) = mask_hash_comments(text, options.allow_hash_comments);
+    let mut canonical = String::new();
+    match nojson::RawJson::parse_jsonc(&masked) {
+        Ok((json, _)) => write_canonical(&mut canonical, json.value()),
+        Err(nojson::JsonParseError::UnexpectedTrailingChar { position, .. })
+            if options.trailing_content.is_some() =>
+        {
+            return canonical_hash(&text[..position], options);
+        }
+        Err(_) if options.allow_comment_only && parse_comment_only(&masked).is_some() => {}
+        Err(e) => return Err(FormatError::Parse(format_json_parse_error(text, e))),
+    }
+    Ok(sha256(canonical.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+fn write_canonical(output: &mut String, value: nojson::RawJsonValue<'_, '_>) {
+    match value.kind() {
+        nojson::JsonValueKind::Integer | nojson::JsonValueKind::Float => {
+            match format_js_number(value.as_raw_str()) {
+                Some(number) => output.push_str(&number),
+                None => output.push_str(value.as_raw_str()),
+            }
+        }
+        nojson::JsonValueKind::String => {
+            let content = value.to_unquoted_string_str().expect("bug");
+            output.push_str(&quote_string(&content));
+        }
+        nojson::JsonValueKind::Array => {
+            output.push('[');
+            for (i, element) in value.to_array().expect("bug").enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                write_canonical(output, element);
+            }
+            output.push(']');
+        }
+        nojson::JsonValueKind::Object => {
+            let mut members = value
+                .to_object()
+                .expect("bug")
+                .map(|(key, value)| (key.to_unquoted_string_str().expect("bug"), value))
+                .collect::<Vec<_>>();
+            members.sort_by(|a, b| a.0.cmp(&b.0));
+            output.push('{');
+            for (i, (key, value)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                output.push_str(&quote_string(&key));
+                output.push(':');
+                write_canonical(output, value);
+            }
+            output.push('}');
+        }
+        nojson::JsonValueKind::Null | nojson::JsonValueKind::Boolean => {
+            output.push_str(value.as_raw_str());
+        }
+    }
+}
+
+// SHA-256 as specified in FIPS 180-4.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (chunk, word) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+// Representative documents checked by `selfcheck()`.
+const SELFCHECK_FIXTURES: &[(&str, &str)] = &[
+    ("example", include_str!("../example.jsonc")),
+    (
+        "member-comments",
+        "{\n  // Comment before key\n  \"key1\": \"value1\", // Trailing comment\n  /* Block comment */\n  \"key2\": \"value2\"\n}",
+    ),
+    ("trailing-comma", "[\n  1,\n  2,\n  3,\n]"),
+    (
+        "comment-before-trailing-comma",
+        "{\n  \"key1\": \"value1\", // Comment after value\n  // Final comment before trailing comma\n}",
+    ),
+    (
+        "blank-lines",
+        "{\n\n\n  \"key\"   :    \"value\"   ,\n\n\n  \"another\"  :   42\n\n\n}",
+    ),
+    ("inline-block-comment", "{/*foo*/\"bar\":\"baz\"}"),
+    (
+        "multiline-block-comment",
+        "/* c\n   ] */{/* b */\"k0\":3.5,\t} ",
+    ),
+    ("scalar-root", " // d\n\n\"a\" "),
+];
+
+/// Outcome of checking one fixture with one set of options in [`selfcheck()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfcheckResult {
+    /// Name of the fixture.
+    pub fixture: &'static str,
+
+    /// Name of the options (e.g., `"sort-keys"`).
+    pub options: &'static str,
+
+    /// Description of the problem, if the check failed.
+    pub error: Option<String>,
+}
+
+/// Formats a built-in set of representative documents with several sets of options, and checks
+/// that each output has the same values and comments as its input and formats to itself.
+///
+/// This is a smoke test of the formatter's invariants, to be run when a bug is suspected.
+pub fn selfcheck() -> Vec<SelfcheckResult> {
+    let mut results = Vec::new();
+    for &(fixture, text) in SELFCHECK_FIXTURES {
+        for (name, options) in selfcheck_option_sets() {
+            results.push(SelfcheckResult {
+                fixture,
+                options: name,
+                error: check_formatting(text, &options).err(),
+            });
+        }
+    }
+    results
+}
+
+// Returns the option sets that `selfcheck()` formats each fixture with.
+fn selfcheck_option_sets() -> [(&'static str, FormatOptions); 4] {
+    [
+        ("default", FormatOptions::default()),
+        (
+            "strip",
+            FormatOptions {
+                strip: true,
+                ..Default::default()
+            },
+        ),
+        (
+            "sort-keys",
+            FormatOptions {
+                sort_keys: Some(KeyOrder::Lexical),
+                ..Default::default()
+            },
+        ),
+        (
+            "reflow",
+            FormatOptions {
+                max_width: Some(40),
+                collapse_width: Some(30),
+                array_rows_threshold: Some(3),
+                object_rows_threshold: Some(3),
+                hug_closing_brackets: true,
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+// Checks that formatting `text` keeps its values and comments (except those removed by
+// `strip`, and in any order with `sort_keys`), and that the output is already formatted.
+fn check_formatting(text: &str, options: &FormatOptions) -> Result<(), String> {
+    let (json, comment_ranges) =
+        nojson::RawJson::parse_jsonc(text).map_err(|e| format!("invalid input: {e}"))?;
+    let output = format_text(text, options).map_err(|e| e.to_string())?;
+    let (formatted, formatted_comment_ranges) =
+        nojson::RawJson::parse_jsonc(&output).map_err(|e| format!("invalid output: {e}"))?;
+
+    let canonical = |value| {
+        let mut canonical = String::new();
+        write_canonical(&mut canonical, value);
+        canonical
+    };
+    if canonical(json.value()) != canonical(formatted.value()) {
+        return Err("the values changed".to_owned());
+    }
+
+    let mut expected_comments = if options.strip {
+        Vec::new()
+    } else {
+        comment_texts(text, &comment_ranges)
+    };
+    let mut actual_comments = comment_texts(&output, &formatted_comment_ranges);
+    if options.sort_keys.is_some() {
+        expected_comments.sort();
+        actual_comments.sort();
+    }
+    if expected_comments != actual_comments {
+        return Err("the comments changed".to_owned());
+    }
+
+    if format_text(&output, options).map_err(|e| e.to_string())? != output {
+        return Err("the output is not idempotent".to_owned());
+    }
+    if output.lines().any(|line| line != line.trim_end()) {
+        return Err("the output has trailing whitespace".to_owned());
+    }
+    Ok(())
+}
+
+// Returns the comments with their whitespace removed, as reformatting may change it.
+fn comment_texts(text: &str, comment_ranges: &[Range<usize>]) -> Vec<String> {
+    comment_ranges
+        .iter()
+        .map(|r| text[r.clone()].split_whitespace().collect())
+        .collect()
+}
+
+/// Parse error diagnostic produced by [`parse_diagnostics()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// 1-based line number.
+    pub line: usize,
+
+    /// 1-based column number (in characters).
+    pub column: usize,
+
+    /// Error message.
+    pub message: String,
+}
+
+impl nojson::DisplayJson for Diagnostic {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.object(|f| {
+            f.member("severity", "error")?;
+            f.member("line", self.line)?;
+            f.member("column", self.column)?;
+            f.member("message", &self.message)
+        })
+    }
+}
+
+/// Parses the input and returns the errors found (empty if the input is valid JSONC).
+///
+/// The options that relax parsing ([`FormatOptions::allow_hash_comments`],
+/// [`FormatOptions::trailing_content`] and [`FormatOptions::allow_comment_only`]) are applied
+/// as in [`format_detailed()`], so the input is reported as valid exactly when it can be
+/// formatted.
+pub fn parse_diagnostics(input: &[u8], options: &FormatOptions) -> Vec<Diagnostic> {
+    let text = match std::str::from_utf8(input) {
+        Ok(text) => text,
+        Err(e) => {
+            let valid = std::str::from_utf8(&input[..e.valid_up_to()]).expect("bug");
+            let last_line = valid.rsplit('\n').next().expect("bug");
+            return vec![Diagnostic {
+                line: valid.matches('\n').count() + 1,
+                column: last_line.chars().count() + 1,
+                message: FormatError::InvalidUtf8(e).to_string(),
+            }];
+        }
+    };
+    let Some(error) = find_parse_error(text, options) else {
+        return Vec::new();
+    };
+    let (line, column) = error
+        .get_line_and_column_numbers(text)
+        .unwrap_or((NonZeroUsize::MIN, NonZeroUsize::MIN));
+    vec![Diagnostic {
+        line: line.get(),
+        column: column.get(),
+        message: error.to_string(),
+    }]
+}
+
+// Returns the error that makes `format_detailed()` reject the text, if any.
+fn find_parse_error(text: &str, options: &FormatOptions) -> Option<nojson::JsonParseError> {
+    let (masked, _) = mask_hash_comments(text, options.allow_hash_comments);
+    let error = nojson::RawJson::parse_jsonc(&masked).err()?;
+    if let (nojson::JsonParseError::UnexpectedTrailingChar { position, .. }, Some(_)) =
+        (&error, options.trailing_content)
+    {
+        return find_parse_error(&text[..*position], options);
+    }
+    if options.allow_comment_only && parse_comment_only(&masked).is_some() {
+        return None;
+    }
+    Some(error)
+}
+
+#[derive(Debug)]
+struct ColumnWriter<W> {
+    inner: W,
+    column: usize,
+}
+
+impl<W: std::fmt::Write> std::fmt::Write for ColumnWriter<W> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        match s.rfind('\n') {
+            Some(i) => self.column = s[i + 1..].chars().count(),
+            None => self.column += s.chars().count(),
+        }
+        self.inner.write_str(s)
+    }
+}
+
+/// Formatter that writes a parsed JSONC value to a [`std::fmt::Write`].
+///
+/// This is the lower-level API behind [`format_text()`], for callers that have already parsed
+/// the text with [`nojson::RawJson::parse_jsonc()`]. Unlike [`format_text()`], it always writes
+/// LF line endings and doesn't handle [`FormatOptions::changed_lines`],
+/// [`FormatOptions::allow_hash_comments`], [`FormatOptions::allow_comment_only`] or
+/// [`FormatOptions::trailing_content`].
+#[derive(Debug)]
+pub struct Formatter<'a, W> {
+    text: &'a str,
+    comment_ranges: BTreeMap<usize, usize>,
+    // Comments removed with `strip_comments_to_blank_lines`, which are left out of
+    // `comment_ranges` so that the layout is the same as with plain stripping.
+    blank_comment_ranges: BTreeMap<usize, usize>,
+    writer: ColumnWriter<W>,
+    level: usize,
+    text_position: usize,
+    multiline_mode: bool,
+    options: &'a FormatOptions,
+    comment_column: Option<usize>,
+    measured_comment_columns: Option<Vec<usize>>,
+    // Text position just after the most recent closing bracket of a multiline container.
+    multiline_close_end: Option<usize>,
+    // Layout directive comments, keyed by their end positions.
+    layout_directives: BTreeMap<usize, LayoutDirective>,
+    in_collapsed_container: bool,
+    // Keys and indices from the root value to the value being formatted.
+    path: Vec<PathSegment>,
+}
+
+/// Key or index that leads from a container to one of its values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathSegment {
+    /// Text range of the (quoted and escaped) key of an object member.
+    Key(Range<usize>),
+    /// Index of an array element.
+    Index(usize),
+}
+
+impl PathSegment {
+    fn key(key: nojson::RawJsonValue<'_, '_>) -> Self {
+        PathSegment::Key(key.position()..key.position() + key.as_raw_str().len())
+    }
+
+    // Returns whether this segment matches a (escaped) JSON Pointer reference token.
+    //
+    // Keys are only unescaped here, as most paths are never compared.
+    fn matches_token(&self, text: &str, token: &str) -> bool {
+        match self {
+            PathSegment::Key(range) => {
+                let token = token.replace("~1", "/").replace("~0", "~");
+                nojson::RawJson::parse(&text[range.clone()]).is_ok_and(|json| {
+                    json.value()
+                        .to_unquoted_string_str()
+                        .is_ok_and(|key| key == token)
+                })
+            }
+            PathSegment::Index(i) => token == i.to_string(),
+        }
+    }
+}
+
+// Object member formatted on its own by `format_sorted_members()`, to be written in key order.
+#[derive(Debug)]
+struct SortedMember<'k> {
+    // Unescaped key.
+    key: Cow<'k, str>,
+    // Comments before the key.
+    leading: String,
+    // Key and value.
+    body: String,
+    // Comments after the value (and its comma) on the same line.
+    trailing: String,
+    ends_with_multiline_close: bool,
+}
+
+/// Comment such as `/* jcfmt:expand */` that overrides the layout of the container after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LayoutDirective {
+    Expand,
+    Collapse,
+}
+
+impl<'a, W: std::fmt::Write> Formatter<'a, W> {
+    /// Makes a formatter for `text` with the given options.
+    ///
+    /// `comment_ranges` must be the byte ranges of all the comments in `text`, as returned by
+    /// [`nojson::RawJson::parse_jsonc()`] for the same text: each range covers one whole
+    /// comment (excluding the newline that ends a `//` comment), and the ranges don't overlap.
+    /// Comments missing from `comment_ranges` are dropped from the output, and ranges that
+    /// don't match `text` give broken output.
+    pub fn with_options(
+        text: &'a str,
+        mut comment_ranges: Vec<Range<usize>>,
+        writer: W,
+        options: &'a FormatOptions,
+    ) -> Self {
+        let layout_directives = comment_ranges
+            .iter()
+            .filter_map(|r| {
+                let content = text[r.clone()].strip_prefix("/*")?.strip_suffix("*/")?;
+                let directive = match content.trim() {
+                    "jcfmt:expand" => LayoutDirective::Expand,
+                    "jcfmt:collapse" => LayoutDirective::Collapse,
+                    _ => return None,
+                };
+                Some((r.end, directive))
+            })
+            .collect();
+        let mut blank_comment_ranges = Vec::new();
+        if options.strip {
+            let removed;
+            (comment_ranges, removed) = comment_ranges.into_iter().partition(|r| {
+                let comment = &text[r.clone()];
+                options
+                    .preserved_comment_prefixes
+                    .iter()
+                    .any(|prefix| comment.starts_with(prefix.as_str()))
+            });
+            if options.strip_comments_to_blank_lines {
+                blank_comment_ranges = removed;
+            }
+        }
+        Self {
+            text,
+            comment_ranges: comment_ranges
+                .into_iter()
+                .map(|r| (r.start, r.end))
+                .collect(),
+            blank_comment_ranges: blank_comment_ranges
+                .into_iter()
+                .map(|r| (r.start, r.end))
+                .collect(),
+            writer: ColumnWriter {
+                inner: writer,
+                column: 0,
+            },
+            level: 0,
+            text_position: 0,
+            multiline_mode: false,
+            options,
+            comment_column: None,
+            measured_comment_columns: None,
+            multiline_close_end: None,
+            layout_directives,
+            in_collapsed_container: false,
+            path: Vec::new(),
+        }
+    }
+
+    /// Formats `value`, which must be the root value of `text`, with its comments.
+    ///
+    /// The comments are consumed as they are written, so a formatter formats a single value.
+    pub fn format(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        self.multiline_mode = self.is_newline_needed(value, 0);
+        if !self.multiline_mode && self.contains_comment(value.position()) {
+            // Leading comments of a single-line root value.
+            self.format_comments(value.position())?;
+            self.indent(value.position())?;
+        }
+        self.format_value(value)?;
+        self.format_comments(self.text.len())?;
+        if self.options.append_stats {
+            let mut stats = ValueStats::default();
+            stats.add(value);
+            write!(self.writer, "\n/* {stats} */")?;
+        }
+        for _ in 0..self.trailing_newlines() {
+            writeln!(self.writer)?;
+        }
+        Ok(())
+    }
+
+    fn format_comments_only(&mut self) -> std::fmt::Result {
+        self.format_comments(self.text.len())?;
+        for _ in 0..self.trailing_newlines() {
+            writeln!(self.writer)?;
+        }
+        Ok(())
+    }
+
+    fn trailing_newlines(&self) -> usize {
+        if !self.options.trailing_newline_if_missing_only {
+            return 1;
+        }
+        let trailing = &self.text[self.text.trim_end().len()..];
+        trailing.matches('\n').count().max(1)
+    }
+
+    // Formats an object member (if `key` is given) or array element on its own, writing the
+    // indentation of its line first if `at_line_start`.
+    fn format_fragment(
+        &mut self,
+        key: Option<nojson::RawJsonValue<'_, '_>>,
+        value: nojson::RawJsonValue<'_, '_>,
+        path: Vec<PathSegment>,
+        at_line_start: bool,
+    ) -> std::fmt::Result {
+        let start = fragment_start(key, value);
+        self.level = path.len();
+        self.path = path;
+        self.text_position = start;
+        if at_line_start {
+            let indent = self.options.indent_width(self.level);
+            write!(self.writer, "{:indent$}", "")?;
+        } else {
+            self.writer.column = self.text[..start]
+                .rsplit('\n')
+                .next()
+                .expect("bug")
+                .chars()
+                .count();
+        }
+        let Some(key) = key else {
+            self.multiline_mode = self.is_newline_needed(value, self.writer.column);
+            return self.format_value_content(value);
+        };
+        self.multiline_mode = false;
+        self.format_key(key)?;
+        self.format_symbol(':')?;
+        self.format_member_value(value)
+    }
+
+    fn format_value(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        if self.multiline_mode {
+            self.format_comments(value.position())?;
+            self.indent(value.position())?;
+        }
+        self.format_value_content(value)?;
+        Ok(())
+    }
+
+    fn format_key(&mut self, key: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        if self.multiline_mode {
+            self.format_comments(key.position())?;
+            self.indent(key.position())?;
+        }
+        write!(self.writer, "{}", self.scalar_text(key))?;
+        self.text_position = key.position() + key.as_raw_str().len();
+        Ok(())
+    }
+
+    fn format_member_value(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        if self.contains_comment(value.position()) {
+            self.format_comments(value.position())?;
+            self.indent(value.position())?;
+        } else {
+            write!(self.writer, " ")?;
+        }
+        self.format_value_content(value)?;
+        Ok(())
+    }
+
+    fn format_value_content(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        match value.kind() {
+            _ if self.is_replaced_with_null(value) => write!(self.writer, "null")?,
+            nojson::JsonValueKind::Null
+            | nojson::JsonValueKind::Boolean
+            | nojson::JsonValueKind::Integer
+            | nojson::JsonValueKind::Float
+            | nojson::JsonValueKind::String => write!(self.writer, "{}", self.scalar_text(value))?,
+            nojson::JsonValueKind::Array | nojson::JsonValueKind::Object => {
+                let old_in_collapsed_container = self.in_collapsed_container;
+                if self.is_collapse_forced(value) {
+                    self.in_collapsed_container = true;
+                }
+                if value.kind() == nojson::JsonValueKind::Array {
+                    self.format_array(value)?;
+                } else {
+                    self.format_object(value)?;
+                }
+                self.in_collapsed_container = old_in_collapsed_container;
+            }
+        }
+        self.text_position = value.position() + value.as_raw_str().len();
+        Ok(())
+    }
+
+    fn has_trailing_comma(&self, close_position: usize) -> bool {
+        let Some(mut position) = self.text[self.text_position..close_position].find(',') else {
+            return false;
+        };
+        position += self.text_position;
+        while self
+            .comment_ranges
+            .range(..position)
+            .next_back()
+            .is_some_and(|(_, &comment_end)| position < comment_end)
+        {
+            position += 1;
+            let Some(offset) = self.text[position..close_position].find(',') else {
+                return false;
+            };
+            position += offset;
+        }
+        true
+    }
+
+    // Returns the position just after the next `ch` that is not inside a comment.
+    fn find_symbol(&self, ch: char) -> usize {
+        let mut position =
+            self.text_position + self.text[self.text_position..].find(ch).expect("bug") + 1;
+        while self
+            .comment_ranges
+            .range(..position)
+            .next_back()
+            .is_some_and(|(_, &end)| position < end)
+        {
+            position += self.text[position..].find(ch).expect("bug") + 1;
+        }
+        position
+    }
+
+    fn format_symbol(&mut self, ch: char) -> std::fmt::Result {
+        let position = self.find_symbol(ch);
+        let is_close = matches!(ch, ']' | '}');
+        let hug = is_close
+            && self.multiline_mode
+            && self.options.hug_closing_brackets
+            && self.multiline_close_end == Some(self.text_position)
+            && !self.contains_comment(position);
+        if hug {
+            // Nothing to write between this and the previous closing bracket.
+        } else if is_close && self.multiline_mode || self.contains_comment(position) {
+            let trailing_comments = self
+                .comment_ranges
+                .range(self.text_position..position)
+                .map(|(&start, _)| start)
+                .collect::<Vec<_>>();
+            self.format_trailing_comment(position)?;
+            let last_trailing_comment = trailing_comments
+                .into_iter()
+                .rfind(|start| !self.comment_ranges.contains_key(start));
+
+            // A symbol (other than a closing bracket) can follow a block comment on the same line.
+            let same_line = !is_close
+                && !self.contains_comment(position)
+                && last_trailing_comment.is_some_and(|start| {
+                    self.text[start..].starts_with("/*") || self.auto_block_comment(start).is_some()
+                });
+            if !same_line {
+                self.format_leading_comment(position)?;
+                let level = self.level;
+                if is_close {
+                    self.text_position = position - 1;
+                    if self.options.closing_bracket_indent == ClosingBracketIndent::MatchContent {
+                        self.level += 1;
+                    }
+                }
+                self.indent(position)?;
+                self.level = level;
+            }
+        }
+
+        write!(self.writer, "{ch}")?;
+        self.text_position = position;
+        if is_close && self.multiline_mode {
+            self.multiline_close_end = Some(position);
+        }
+        Ok(())
+    }
+
+    fn contains_comment(&self, position: usize) -> bool {
+        self.comment_ranges.range(..position).next().is_some()
+    }
+
+    fn format_comments(&mut self, position: usize) -> std::fmt::Result {
+        self.format_trailing_comment(position)?;
+        self.format_leading_comment(position)?;
+        Ok(())
+    }
+
+    fn format_leading_comment(&mut self, position: usize) -> std::fmt::Result {
+        loop {
+            let next = self
+                .comment_ranges
+                .range(..position)
+                .next()
+                .map(|x| (*x.0, *x.1));
+            if let Some((&start, &end)) = self.blank_comment_ranges.range(..position).next()
+                && next.is_none_or(|(comment_start, _)| start < comment_start)
+            {
+                self.format_comment_as_blank_lines(start, end)?;
+                continue;
+            }
+            let Some((comment_start, comment_end)) = next else {
+                return Ok(());
+            };
+
+            let preserved = self.is_preserved_comment(comment_start);
+
+            self.indent(comment_start)?;
+            self.text_position = comment_start;
+            let comment = &self.text[comment_start..comment_end];
+            if comment.starts_with("//") || comment.starts_with('#') || preserved {
+                write!(self.writer, "{}", comment.trim_end())?;
+            } else {
+                let after_indent = self.options.indent_width(self.level);
+                let before_indent = self.text[..comment_start]
+                    .rsplit('\n')
+                    .next()
+                    .expect("bug")
+                    .len();
+                for (i, mut line) in comment.lines().enumerate() {
+                    if i == 0 {
+                        write!(self.writer, "{}", line.trim())?;
+                    } else if let Some(delta) = after_indent.checked_sub(before_indent) {
+                        write!(
+                            self.writer,
+                            "\n{:width$}{}",
+                            "",
+                            line.trim_end(),
+                            width = delta
+                        )?;
+                    } else {
+                        let delta = before_indent - after_indent;
+                        for _ in 0..delta {
+                            if let Some(l) = line.strip_prefix(' ') {
+                                line = l;
+                            } else {
+                                break;
+                            };
+                        }
+                        write!(self.writer, "\n{}", line.trim_end())?;
+                    }
+                }
+            }
+            self.comment_ranges.remove(&comment_start);
+            self.text_position = comment_end;
+        }
+    }
+
+    // Writes a line break for each line up to the end of the removed comment, so that the
+    // following lines keep their line numbers. If something else follows the comment on its
+    // last line, the line break before it is left to `indent()`.
+    //
+    // Comments that have already been passed (e.g., those inside single-line containers) are
+    // just dropped.
+    fn format_comment_as_blank_lines(
+        &mut self,
+        comment_start: usize,
+        comment_end: usize,
+    ) -> std::fmt::Result {
+        self.blank_comment_ranges.remove(&comment_start);
+        if comment_start < self.text_position {
+            return Ok(());
+        }
+        let mut line_breaks = self.text[self.text_position..comment_end]
+            .matches('\n')
+            .count();
+        if line_breaks > 0 && !self.is_line_end(comment_end) {
+            line_breaks -= 1;
+        }
+        for _ in 0..line_breaks {
+            writeln!(self.writer)?;
+        }
+        self.text_position = comment_end;
+        Ok(())
+    }
+
+    // Returns whether only whitespace and removed comments follow `position` on its line.
+    fn is_line_end(&self, mut position: usize) -> bool {
+        loop {
+            let rest = &self.text[position..];
+            let trimmed = rest.trim_start_matches([' ', '\t', '\r']);
+            position += rest.len() - trimmed.len();
+            match self.blank_comment_ranges.get(&position) {
+                Some(&end) => position = end,
+                None => return trimmed.is_empty() || trimmed.starts_with('\n'),
+            }
+        }
+    }
+
+    fn format_trailing_comment(&mut self, next_position: usize) -> std::fmt::Result {
+        if self.text_position == 0 {
+            return Ok(());
+        };
+        loop {
+            let Some((comment_start, comment_end)) = self
+                .comment_ranges
+                .range(self.text_position..next_position)
+                .next()
+                .map(|x| (*x.0, *x.1))
+            else {
+                return Ok(());
+            };
+            if self.text[self.text_position..comment_end].contains('\n') {
+                return Ok(());
+            }
+
+            if self.options.strip && !self.is_preserved_comment(comment_start) {
+                self.comment_ranges.remove(&comment_start);
+                self.text_position = comment_end;
+                continue;
+            }
+
+            let comment = match self.auto_block_comment(comment_start) {
+                Some(block) => Cow::Owned(block),
+                None => Cow::Borrowed(self.text[comment_start..comment_end].trim_end()),
+            };
+            let mut padding = match self.comment_column.take() {
+                Some(column) => {
+                    if let Some(columns) = &mut self.measured_comment_columns {
+                        columns.push(self.writer.column);
+                    }
+                    column.saturating_sub(self.writer.column).max(1)
+                }
+                None => 1,
+            };
+            if self.options.trailing_comment_style == Some(TrailingCommentStyle::Auto)
+                && comment.starts_with("//")
+                && let Some(max_width) = self.options.max_width
+            {
+                let end_column = |padding| self.writer.column + padding + comment.chars().count();
+                if end_column(padding) > max_width {
+                    padding = 1;
+                }
+                if end_column(padding) > max_width {
+                    let indent = self.options.indent_width(self.level);
+                    write!(self.writer, "\n{:indent$}", "")?;
+                    padding = 0;
+                }
+            }
+            write!(self.writer, "{:width$}{comment}", "", width = padding)?;
+            self.comment_ranges.remove(&comment_start);
+            self.text_position = comment_end;
+        }
+    }
+
+    // Returns the `/* */` form of the `//` comment starting at `comment_start` if
+    // `TrailingCommentStyle::Auto` converts it, which it does if a comma follows it.
+    fn auto_block_comment(&self, comment_start: usize) -> Option<String> {
+        if self.options.trailing_comment_style != Some(TrailingCommentStyle::Auto)
+            || self.is_preserved_comment(comment_start)
+        {
+            return None;
+        }
+        let rest = self.text[comment_start..].strip_prefix("//")?;
+        let (content, after) = rest.split_once('\n')?;
+        let content = content.trim();
+        if content.contains("*/") || !after.trim_start().starts_with(',') {
+            return None;
+        }
+        Some(format!("/* {content} */"))
+    }
+
+    // Returns whether the comment is written as a comment that extends to the end of its line.
+    fn is_line_comment(&self, comment_start: usize) -> bool {
+        let comment = &self.text[comment_start..];
+        (comment.starts_with("//") || comment.starts_with('#'))
+            && self.auto_block_comment(comment_start).is_none()
+    }
+
+    fn is_preserved_comment(&self, comment_start: usize) -> bool {
+        let comment = &self.text[comment_start..];
+        self.options
+            .preserved_comment_prefixes
+            .iter()
+            .any(|prefix| comment.starts_with(prefix.as_str()))
+    }
+
+    fn format_array(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        self.format_symbol('[')?;
+        self.level += 1;
+
+        let old_multiline_mode = self.multiline_mode;
+        self.multiline_mode = self.is_newline_needed(value, self.writer.column - 1);
+        let limit = self.options.preview_array_limit.unwrap_or(usize::MAX);
+        let mut omitted = 0;
+        for (i, element) in value.to_array().expect("bug").enumerate() {
+            if i >= limit {
+                omitted += 1;
+                continue;
+            }
+            if i > 0 {
+                self.format_symbol(',')?;
+                if !self.multiline_mode {
+                    write!(self.writer, " ")?;
+                }
+            }
+            self.path.push(PathSegment::Index(i));
+            self.format_value(element)?;
+            self.path.pop();
+        }
+        let close_position = value.position() + value.as_raw_str().len();
+        if omitted > 0 {
+            self.format_omitted_elements(limit, omitted, close_position)?;
+        } else if !self.options.strip && self.has_trailing_comma(close_position) {
+            self.format_symbol(',')?;
+        }
+        self.format_comments(close_position)?;
+
+        self.level -= 1;
+        self.format_symbol(']')?;
+        self.multiline_mode = old_multiline_mode;
+        Ok(())
+    }
+
+    // Skips the elements after the first `shown` ones (and their comments),
+    // writing a summary comment in their place.
+    fn format_omitted_elements(
+        &mut self,
+        shown: usize,
+        omitted: usize,
+        close_position: usize,
+    ) -> std::fmt::Result {
+        let skipped_comments = self
+            .comment_ranges
+            .range(self.text_position..close_position)
+            .map(|(&start, _)| start)
+            .collect::<Vec<_>>();
+        for start in skipped_comments {
+            self.comment_ranges.remove(&start);
+        }
+        self.text_position = close_position - 1;
+
+        if shown > 0 {
+            write!(self.writer, ",")?;
+        }
+        if self.multiline_mode {
+            let width = self.options.indent_width(self.level);
+            write!(self.writer, "\n{:width$}", "")?;
+        } else if shown > 0 {
+            write!(self.writer, " ")?;
+        }
+        write!(self.writer, "/* ... {omitted} more */")
+    }
+
+    fn format_object(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        self.format_symbol('{')?;
+        self.level += 1;
+
+        let old_multiline_mode = self.multiline_mode;
+        self.multiline_mode = self.is_newline_needed(value, self.writer.column - 1);
+        if let Some(order) = self.options.sort_keys
+            && self.is_sort_target()
+        {
+            self.format_sorted_members(value, order)?;
+        } else {
+            let comment_column = self.comment_align_column(value)?;
+            self.format_members(value, comment_column)?;
+        }
+
+        self.level -= 1;
+        self.format_symbol('}')?;
+        self.multiline_mode = old_multiline_mode;
+        Ok(())
+    }
+
+    // Returns whether the object being formatted is one of `sort_keys_at` (if given).
+    fn is_sort_target(&self) -> bool {
+        let Some(pointers) = &self.options.sort_keys_at else {
+            return true;
+        };
+        pointers.iter().any(|pointer| {
+            let mut tokens = pointer.split('/');
+            tokens.next().is_some_and(str::is_empty)
+                && pointer.matches('/').count() == self.path.len()
+                && tokens
+                    .zip(&self.path)
+                    .all(|(token, segment)| segment.matches_token(self.text, token))
+        })
+    }
+
+    // Writes the `//` comments just before `key` as a `"<key>__comment"` member instead.
+    fn format_comment_field(&mut self, key: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
+        self.format_trailing_comment(key.position())?;
+        let leading_comments = self
+            .comment_ranges
+            .range(..key.position())
+            .map(|(&start, &end)| (start, end))
+            .collect::<Vec<_>>();
+        let line_comments_start = leading_comments
+            .iter()
+            .rposition(|&(start, _)| {
+                !self.text[start..].starts_with("//") || self.is_preserved_comment(start)
+            })
+            .map_or(0, |i| i + 1);
+        let line_comments = &leading_comments[line_comments_start..];
+        let (Some(&(first_start, _)), Some(&(_, last_end))) =
+            (line_comments.first(), line_comments.last())
+        else {
+            return Ok(());
+        };
+
+        // Other comments before them stay as they are.
+        self.format_leading_comment(first_start)?;
+
+        let content = line_comments
+            .iter()
+            .map(|&(start, end)| self.text[start + "//".len()..end].trim())
+            .collect::<Vec<_>>()
+            .join("\n");
+        for (start, _) in line_comments {
+            self.comment_ranges.remove(start);
+        }
+        let field = format!("{}__comment", key.to_unquoted_string_str().expect("bug"));
+        self.indent(first_start)?;
+        write!(
+            self.writer,
+            "{}: {},",
+            quote_string(&field),
+            quote_string(&content)
+        )?;
+        self.text_position = last_end;
+        Ok(())
+    }
+
+    fn format_members(
+        &mut self,
+        value: nojson::RawJsonValue<'_, '_>,
+        comment_column: Option<usize>,
+    ) -> std::fmt::Result {
+        for (i, (key, value)) in value.to_object().expect("bug").enumerate() {
+            if i > 0 {
+                self.format_symbol(',')?;
+                if !self.multiline_mode {
+                    write!(self.writer, " ")?;
+                }
+            }
+
+            if self.options.comments_to_fields && !self.options.strip {
+                self.format_comment_field(key)?;
+            }
+            self.format_key(key)?;
+            self.comment_column = None;
+            self.format_symbol(':')?;
+            self.path.push(PathSegment::key(key));
+            self.format_member_value(value)?;
+            self.path.pop();
+            self.comment_column = comment_column;
+        }
+        let close_position = value.position() + value.as_raw_str().len();
+        if !self.options.strip && self.has_trailing_comma(close_position) {
+            self.format_symbol(',')?;
+        }
+        self.format_comments(close_position)?;
+        self.comment_column = None;
+        Ok(())
+    }
+
+    // Formats each member (with its leading and trailing comments) separately in source order,
+    // then writes them out in key order.
+    //
+    // With `sort_keys_keep_comments_fixed`, the standalone comments preceding the N-th member
+    // in the source are written before the N-th member in the output instead.
+    fn format_sorted_members(
+        &mut self,
+        value: nojson::RawJsonValue<'_, '_>,
+        order: KeyOrder,
+    ) -> std::fmt::Result {
+        let members = value.to_object().expect("bug").collect::<Vec<_>>();
+        let close_position = value.position() + value.as_raw_str().len();
+
+        // Comments on the line of `{` stay there instead of moving with the first member.
+        let first_position = members
+            .first()
+            .map_or(close_position, |(key, _)| key.position());
+        self.format_trailing_comment(first_position)?;
+
+        let comment_ranges = std::mem::take(&mut self.comment_ranges);
+        let mut formatter = self.scratch_formatter(comment_ranges);
+        let mut has_trailing_comma = false;
+        let mut pending = String::new();
+        let mut pieces = Vec::new();
+        for (i, &(key, value)) in members.iter().enumerate() {
+            if self.options.comments_to_fields && !self.options.strip {
+                formatter.format_comment_field(key)?;
+            }
+            formatter.format_comments(key.position())?;
+            let leading =
+                std::mem::take(&mut pending) + &std::mem::take(&mut formatter.writer.inner);
+            formatter.format_key(key)?;
+            formatter.format_symbol(':')?;
+            formatter.path.push(PathSegment::key(key));
+            formatter.format_member_value(value)?;
+            formatter.path.pop();
+            let body = std::mem::take(&mut formatter.writer.inner);
+            let ends_with_multiline_close =
+                formatter.multiline_close_end == Some(formatter.text_position);
+
+            let next_position = members
+                .get(i + 1)
+                .map_or(close_position, |(key, _)| key.position());
+            let mut trailing = String::new();
+            let mut ends_with_line_comment = false;
+            if i + 1 < members.len() || formatter.has_trailing_comma(close_position) {
+                // Comments between the value and the comma: those on the value's line stay
+                // there, and the others become leading comments of the next member.
+                let position = formatter.find_symbol(',');
+                let comments = formatter
+                    .comment_ranges
+                    .range(formatter.text_position..position)
+                    .map(|(&start, _)| start)
+                    .collect::<Vec<_>>();
+                formatter.format_trailing_comment(position)?;
+                trailing = std::mem::take(&mut formatter.writer.inner);
+                ends_with_line_comment = !trailing.is_empty()
+                    && comments
+                        .into_iter()
+                        .rfind(|start| !formatter.comment_ranges.contains_key(start))
+                        .is_some_and(|start| formatter.is_line_comment(start));
+                formatter.format_leading_comment(position)?;
+                pending = std::mem::take(&mut formatter.writer.inner);
+                formatter.text_position = position;
+                has_trailing_comma = i + 1 == members.len();
+            }
+            formatter.format_trailing_comment(next_position)?;
+            let after_comma = std::mem::take(&mut formatter.writer.inner);
+            if ends_with_line_comment && !after_comma.is_empty() {
+                // A line comment before the comma would swallow the comment after it.
+                let indent = self.options.indent_width(self.level);
+                pending = format!("\n{:indent$}{}{pending}", "", after_comma.trim_start());
+            } else {
+                trailing += &after_comma;
+            }
+
+            let key = key.to_unquoted_string_str().expect("bug");
+            pieces.push(SortedMember {
+                key,
+                leading,
+                body,
+                trailing,
+                ends_with_multiline_close,
+            });
+        }
+        self.comment_ranges = formatter.comment_ranges;
+        self.blank_comment_ranges = formatter.blank_comment_ranges;
+        self.text_position = formatter.text_position;
+
+        let mut fixed_leadings = Vec::new();
+        if self.options.sort_keys_keep_comments_fixed {
+            for piece in &mut pieces {
+                fixed_leadings.push(std::mem::take(&mut piece.leading));
+            }
+        }
+        pieces.sort_by(|a, b| order.compare(&a.key, &b.key));
+        let n = pieces.len();
+        let has_comma = |i: usize| i + 1 < n || (has_trailing_comma && !self.options.strip);
+        if self.options.comment_align_to_value && self.multiline_mode {
+            self.align_sorted_trailing_comments(&mut pieces, has_comma);
+        }
+        let mut last_ends_with_multiline_close = false;
+        for (i, piece) in pieces.into_iter().enumerate() {
+            if i > 0 && !self.multiline_mode {
+                write!(self.writer, " ")?;
+            }
+            let leading = fixed_leadings.get(i).unwrap_or(&piece.leading);
+            write!(self.writer, "{leading}{}", piece.body)?;
+            let comma = has_comma(i);
+            if comma {
+                write!(self.writer, ",")?;
+            }
+            write!(self.writer, "{}", piece.trailing)?;
+            last_ends_with_multiline_close =
+                piece.ends_with_multiline_close && !comma && piece.trailing.is_empty();
+        }
+        write!(self.writer, "{pending}")?;
+        if last_ends_with_multiline_close && pending.is_empty() {
+            self.multiline_close_end = Some(self.text_position);
+        } else {
+            self.multiline_close_end = None;
+        }
+        self.format_comments(close_position)?;
+        Ok(())
+    }
+
+    // Pads the trailing comments of the sorted members so that they start at a common column,
+    // as `comment_align_column` does for members in source order.
+    fn align_sorted_trailing_comments(
+        &self,
+        pieces: &mut [SortedMember<'_>],
+        has_comma: impl Fn(usize) -> bool,
+    ) {
+        let end_column = |i: usize, body: &str| {
+            body.rsplit('\n').next().expect("bug").chars().count() + usize::from(has_comma(i))
+        };
+        let Some(column) = pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| !piece.trailing.is_empty())
+            .map(|(i, piece)| end_column(i, &piece.body) + 1)
+            .max()
+        else {
+            return;
+        };
+        for (i, piece) in pieces.iter_mut().enumerate() {
+            if piece.trailing.is_empty() {
+                continue;
+            }
+            let padding = column.saturating_sub(end_column(i, &piece.body)).max(1);
+            piece.trailing = format!("{:padding$}{}", "", piece.trailing.trim_start());
+        }
+    }
+
+    fn scratch_formatter(&self, comment_ranges: BTreeMap<usize, usize>) -> Formatter<'a, String> {
+        Formatter {
+            text: self.text,
+            comment_ranges,
+            blank_comment_ranges: self.blank_comment_ranges.clone(),
+            writer: ColumnWriter {
+                inner: String::new(),
+                column: self.writer.column,
+            },
+            level: self.level,
+            text_position: self.text_position,
+            multiline_mode: self.multiline_mode,
+            options: self.options,
+            comment_column: None,
+            measured_comment_columns: None,
+            multiline_close_end: None,
+            layout_directives: self.layout_directives.clone(),
+            in_collapsed_container: self.in_collapsed_container,
+            path: self.path.clone(),
+        }
+    }
+
+    // Formats the members in a scratch formatter to find out the output column
+    // where each member's trailing comment would start.
+    fn comment_align_column(
+        &self,
+        value: nojson::RawJsonValue<'_, '_>,
+    ) -> Result<Option<usize>, std::fmt::Error> {
+        if !self.options.comment_align_to_value
+            || !self.multiline_mode
+            || self.measured_comment_columns.is_some()
+        {
+            return Ok(None);
+        }
+
+        let mut formatter = self.scratch_formatter(self.comment_ranges.clone());
+        formatter.measured_comment_columns = Some(Vec::new());
+        formatter.format_members(value, Some(0))?;
+        Ok(formatter
+            .measured_comment_columns
+            .into_iter()
+            .flatten()
+            .max()
+            .map(|column| column + 1))
+    }
+
+    fn is_newline_needed(&self, value: nojson::RawJsonValue<'_, '_>, column: usize) -> bool {
+        if self.in_collapsed_container {
+            return false;
+        }
+        if self.layout_directive(value) == Some(LayoutDirective::Expand)
+            && !is_empty_container(value)
+        {
+            return true;
+        }
+        if self.is_collapse_forced(value) {
+            return false;
+        }
+        self.is_comment_included(value)
+            || self.is_expand_directive_included(value)
+            || self.is_rows_threshold_reached(value)
+            || self.is_layout_newline_needed(value, column)
+            || self.is_max_width_exceeded(value, column)
+    }
+
+    // Returns whether `value` is or contains an array with at least `array_rows_threshold`
+    // elements or an object with at least `object_rows_threshold` members.
+    fn is_rows_threshold_reached(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
+        let reached = |threshold: Option<usize>, count: usize| {
+            threshold.is_some_and(|threshold| count > 0 && count >= threshold)
+        };
+        match value.kind() {
+            nojson::JsonValueKind::Array => {
+                let count = value.to_array().expect("bug").count();
+                reached(self.options.array_rows_threshold, count)
+                    || value
+                        .to_array()
+                        .expect("bug")
+                        .any(|element| self.is_rows_threshold_reached(element))
+            }
+            nojson::JsonValueKind::Object => {
+                let count = value.to_object().expect("bug").count();
+                reached(self.options.object_rows_threshold, count)
+                    || value
+                        .to_object()
+                        .expect("bug")
+                        .any(|(_, value)| self.is_rows_threshold_reached(value))
+            }
+            _ => false,
+        }
+    }
+
+    // Returns whether `value` spans multiple lines in the source and can't be collapsed
+    // (empty containers are always collapsed).
+    // With `array_rows_threshold` (or `object_rows_threshold`), only the layout of the
+    // elements of arrays (or the member values of objects) counts.
+    fn is_layout_newline_needed(&self, value: nojson::RawJsonValue<'_, '_>, column: usize) -> bool {
+        match value.kind() {
+            nojson::JsonValueKind::Array if self.options.array_rows_threshold.is_some() => value
+                .to_array()
+                .expect("bug")
+                .any(|element| self.is_layout_newline_needed(element, column + 1)),
+            nojson::JsonValueKind::Object if self.options.object_rows_threshold.is_some() => {
+                value.to_object().expect("bug").any(|(key, value)| {
+                    let key_width = key.as_raw_str().chars().count();
+                    self.is_layout_newline_needed(value, column + 1 + key_width + 2)
+                })
+            }
+            _ => {
+                self.is_newline_included(value)
+                    && !is_empty_container(value)
+                    && !self.is_collapsible(value, column)
+            }
+        }
+    }
+
+    fn is_collapsible(&self, value: nojson::RawJsonValue<'_, '_>, column: usize) -> bool {
+        self.options
+            .collapse_width
+            .is_some_and(|width| !self.is_width_exceeded(value, column, width))
+    }
+
+    fn is_max_width_exceeded(&self, value: nojson::RawJsonValue<'_, '_>, column: usize) -> bool {
+        self.options
+            .max_width
+            .is_some_and(|width| self.is_width_exceeded(value, column, width))
+    }
+
+    fn is_width_exceeded(
+        &self,
+        value: nojson::RawJsonValue<'_, '_>,
+        column: usize,
+        width: usize,
+    ) -> bool {
+        if is_empty_container(value) {
+            return false;
+        }
+        column + self.single_line_width(value) + self.line_suffix_width(value) > width
+    }
+
+    // Returns the scalar value as it will be written.
+    fn scalar_text<'b>(&self, value: nojson::RawJsonValue<'b, '_>) -> Cow<'b, str> {
+        match value.kind() {
+            nojson::JsonValueKind::String if self.options.normalize_escapes => {
+                match value.to_unquoted_string_str() {
+                    Ok(content) => Cow::Owned(quote_string(&content)),
+                    Err(_) => Cow::Borrowed(value.as_raw_str()),
+                }
+            }
+            nojson::JsonValueKind::Integer | nojson::JsonValueKind::Float => {
+                let number = match self.options.normalize_numbers {
+                    Some(NumberFormat::Js) => format_js_number(value.as_raw_str())
+                        .map_or(Cow::Borrowed(value.as_raw_str()), Cow::Owned),
+                    None => Cow::Borrowed(value.as_raw_str()),
+                };
+                if self.options.numbers_as_strings {
+                    Cow::Owned(format!("\"{number}\""))
+                } else {
+                    number
+                }
+            }
+            _ => Cow::Borrowed(value.as_raw_str()),
+        }
+    }
+
+    // Returns whether `value` (which must not be a key) is written as `null` by `empty_to_null`.
+    fn is_replaced_with_null(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
+        let empty = self.options.empty_to_null;
+        match value.kind() {
+            nojson::JsonValueKind::String => empty.strings && value.as_raw_str() == "\"\"",
+            nojson::JsonValueKind::Array => {
+                empty.arrays && is_empty_container(value) && !self.is_comment_included(value)
+            }
+            nojson::JsonValueKind::Object => {
+                empty.objects && is_empty_container(value) && !self.is_comment_included(value)
+            }
+            _ => false,
+        }
+    }
+
+    // Returns the width of `value` when formatted on a single line.
+    fn single_line_width(&self, value: nojson::RawJsonValue<'_, '_>) -> usize {
+        let (children, separator_width) = match value.kind() {
+            _ if self.is_replaced_with_null(value) => return "null".len(),
+            nojson::JsonValueKind::Array => (
+                value
+                    .to_array()
+                    .expect("bug")
+                    .map(|v| self.single_line_width(v))
+                    .collect::<Vec<_>>(),
+                ", ".len(),
+            ),
+            nojson::JsonValueKind::Object => (
+                value
+                    .to_object()
+                    .expect("bug")
+                    .map(|(k, v)| {
+                        self.scalar_text(k).chars().count() + ": ".len() + self.single_line_width(v)
+                    })
+                    .collect::<Vec<_>>(),
+                ", ".len(),
+            ),
+            _ => return self.scalar_text(value).chars().count(),
+        };
+        let brackets_width = "[]".len();
+        let trailing_comma_width = usize::from(
+            !self.options.strip && {
+                let raw = value.as_raw_str();
+                raw[..raw.len() - 1].trim_end().ends_with(',')
+            },
+        );
+        brackets_width
+            + children.iter().sum::<usize>()
+            + separator_width * children.len().saturating_sub(1)
+            + trailing_comma_width
+    }
+
+    // Returns the width of the comma and trailing comment that follow `value` on the same line.
+    fn line_suffix_width(&self, value: nojson::RawJsonValue<'_, '_>) -> usize {
+        let skip_spaces = |position: usize| {
+            let rest = &self.text[position..];
+            position + rest.len() - rest.trim_start_matches([' ', '\t']).len()
+        };
+        let mut position = value.position() + value.as_raw_str().len();
+        let mut width = 0;
+
+        // A comma is written right after the value, even if it is on a later line.
+        let rest = &self.text[position..];
+        let has_comma = rest.trim_start().starts_with(',');
+        if has_comma {
+            width += 1;
+            position += rest.len() - rest.trim_start().len() + 1;
+        }
+        position = skip_spaces(position);
+        if (!self.options.strip || self.is_preserved_comment(position))
+            && !self.options.max_width_ignore_comments
+            && let Some(&comment_end) = self.comment_ranges.get(&position)
+        {
+            let comment = match self.auto_block_comment(position) {
+                Some(block) => Cow::Owned(block),
+                None => Cow::Borrowed(self.text[position..comment_end].trim_end()),
+            };
+            if !comment.contains('\n') {
+                width += 1 + comment.chars().count();
+                // A comma after a block comment stays on the same line.
+                if !has_comma
+                    && comment.starts_with("/*")
+                    && self.text[skip_spaces(comment_end)..].starts_with(',')
+                {
+                    width += 1;
+                }
+            }
+        }
+        width
+    }
+
+    // Returns the directive comment just before `value`, if any.
+    fn layout_directive(&self, value: nojson::RawJsonValue<'_, '_>) -> Option<LayoutDirective> {
+        let (&end, &directive) = self
+            .layout_directives
+            .range(..=value.position())
+            .next_back()?;
+        self.text[end..value.position()]
+            .trim()
+            .is_empty()
+            .then_some(directive)
+    }
+
+    // Returns whether `value` has a collapse directive that applies, which it doesn't if
+    // `value` contains comments or (when they are stripped) expand directives.
+    fn is_collapse_forced(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
+        self.layout_directive(value) == Some(LayoutDirective::Collapse)
+            && !self.is_comment_included(value)
+            && !self.is_expand_directive_included(value)
+    }
+
+    // Returns whether `value` contains an expand directive that applies to a value (a directive
+    // just before a closing bracket applies to nothing).
+    fn is_expand_directive_included(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
+        let start = value.position();
+        let end = start + value.as_raw_str().len();
+        self.layout_directives
+            .range(start + 1..end)
+            .any(|(&directive_end, &directive)| {
+                directive == LayoutDirective::Expand
+                    && !self.text[directive_end..]
+                        .trim_start()
+                        .starts_with([']', '}'])
+            })
+    }
+
+    fn is_comment_included(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
+        let start = value.position();
+        let end = start + value.as_raw_str().len();
+        self.comment_ranges.range(start..end).next().is_some()
+    }
+
+    fn is_newline_included(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
+        let start = value.position();
+        let end = start + value.as_raw_str().len();
+        self.text[start..end].contains('\n')
+    }
+
+    fn blank_line(&mut self, position: usize) -> std::fmt::Result {
+        let Some(offset) = self.text[self.text_position..position].find('\n') else {
+            return Ok(());
+        };
+        self.text_position += offset + 1;
+
+        let Some(offset) = self.text[self.text_position..position].find('\n') else {
+            return Ok(());
+        };
+        self.text_position += offset + 1;
+
+        writeln!(self.writer)?;
+
+        Ok(())
+    }
+
+    fn indent(&mut self, position: usize) -> std::fmt::Result {
+        if self.text_position == 0 {
+            return Ok(());
+        }
+        self.blank_line(position)?;
+        write!(
+            self.writer,
+            "\n{:width$}",
+            "",
+            width = self.options.indent_width(self.level)
+        )
+    }
+}
+
+// Numbers of the nodes in a JSON value, written by `append_stats`.
+#[derive(Debug, Default)]
+struct ValueStats {
+    objects: usize,
+    arrays: usize,
+    values: usize,
+}
+
+impl ValueStats {
+    fn add(&mut self, value: nojson::RawJsonValue<'_, '_>) {
+        match value.kind() {
+            nojson::JsonValueKind::Array => {
+                self.arrays += 1;
+                for element in value.to_array().expect("bug") {
+                    self.add(element);
+                }
+            }
+            nojson::JsonValueKind::Object => {
+                self.objects += 1;
+                for (_, value) in value.to_object().expect("bug") {
+                    self.add(value);
+                }
+            }
+            _ => self.values += 1,
+        }
+    }
+}
+
+impl std::fmt::Display for ValueStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{} object{}, {} array{}, {} value{}",
+            self.objects,
+            plural(self.objects),
+            self.arrays,
+            plural(self.arrays),
+            self.values,
+            plural(self.values)
+        )
+    }
+}
+
+// Returns whether `value` is an empty array or object, or a scalar.
+fn is_empty_container(value: nojson::RawJsonValue<'_, '_>) -> bool {
+    match value.kind() {
+        nojson::JsonValueKind::Array => value.to_array().expect("bug").next().is_none(),
+        nojson::JsonValueKind::Object => value.to_object().expect("bug").next().is_none(),
+        _ => true,
+    }
+}
+
+fn format_json_parse_error(text: &str, error: nojson::JsonParseError) -> String {
+    let (line_num, column_num) = error
+        .get_line_and_column_numbers(text)
+        .unwrap_or((NonZeroUsize::MIN, NonZeroUsize::MIN));
+    let line = error.get_line(text).unwrap_or("");
+    format_error_with_input(text, &error, line_num, column_num, line)
+}
+
+fn format_failure(text: &str, position: usize, error: std::fmt::Error) -> FormatError {
+    let position = position.min(text.len());
+    let line_start = text[..position].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = text[position..]
+        .find('\n')
+        .map_or(text.len(), |i| position + i);
+    let line = text[line_start..line_end].trim_end_matches('\r');
+    let line_num = NonZeroUsize::MIN.saturating_add(text[..position].matches('\n').count());
+    let column_num = NonZeroUsize::MIN.saturating_add(text[line_start..position].chars().count());
+    FormatError::Fmt(format_error_with_input(
+        text,
+        &format!("failed to format JSON at byte position {position}: {error}"),
+        line_num,
+        column_num,
+        line,
+    ))
+}
+
+fn format_error_with_input(
+    text: &str,
+    error: &dyn std::fmt::Display,
+    line_num: NonZeroUsize,
+    column_num: NonZeroUsize,
+    line: &str,
+) -> String {
+    let prev_line = if line_num.get() == 1 {
+        None
+    } else {
+        text.lines().nth(line_num.get() - 2)
+    };
+
+    let (display_line, display_column) = format_line_around_position(line, column_num.get());
+    let prev_display_line = prev_line.map(|prev| {
+        let (truncated, _) = format_line_around_position(prev, column_num.get());
+        truncated
+    });
+
+    format!(
+        "{error}\n\nINPUT:{}\n{line_num:4} |{display_line}\n     |{:>column$} error",
+        if let Some(prev) = prev_display_line {
+            format!("\n     |{prev}")
+        } else {
+            "".to_owned()
+        },
+        "^",
+        column = display_column
+    )
+}
+
+// Formats a number as ECMAScript's `Number.prototype.toString()` does.
+//
+// Returns `None` if the number is out of the range of `f64`.
+fn format_js_number(raw: &str) -> Option<String> {
+    let number = raw.parse::<f64>().ok().filter(|n| n.is_finite())?;
+    if number == 0.0 {
+        return Some("0".to_owned());
+    }
+
+    // `{:e}` gives the shortest round-tripping digits, e.g., "1.2345e-7".
+    let split_exp_form = |exp_form: String| {
+        let (mantissa, exponent) = exp_form.split_once('e').expect("bug");
+        let digits = mantissa.replace('.', "").trim_end_matches('0').to_owned();
+        (digits, exponent.parse::<i32>().expect("bug") + 1)
+    };
+    let (mut digits, mut n) = split_exp_form(format!("{:e}", number.abs()));
+
+    // When the number lies exactly halfway between two shortest candidates, ECMAScript picks
+    // the even one. The exact decimal expansion of a finite `f64` has at most 767 digits.
+    let (exact_digits, exact_n) = split_exp_form(format!("{:.800e}", number.abs()));
+    if exact_digits.len() == digits.len() + 1 && exact_digits.ends_with('5') {
+        let truncated = &exact_digits[..digits.len()];
+        if truncated.bytes().last().is_some_and(|d| d % 2 == 0) {
+            digits = truncated.trim_end_matches('0').to_owned();
+            n = exact_n;
+        }
+    }
+    let k = digits.len() as i32;
+
+    let sign = if number < 0.0 { "-" } else { "" };
+    let formatted = if k <= n && n <= 21 {
+        format!("{digits}{}", "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 21 {
+        format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{digits}", "0".repeat(-n as usize))
+    } else {
+        let exponent_sign = if n > 0 { "+" } else { "-" };
+        let fraction = if k > 1 {
+            format!(".{}", &digits[1..])
+        } else {
+            String::new()
+        };
+        format!(
+            "{}{fraction}e{exponent_sign}{}",
+            &digits[..1],
+            (n - 1).abs()
+        )
+    };
+    Some(format!("{sign}{formatted}"))
+}
+
+fn quote_string(content: &str) -> String {
+    let mut quoted = String::with_capacity(content.len() + 2);
+    quoted.push('"');
+    for ch in content.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\u{8}' => quoted.push_str("\\b"),
+            '\u{c}' => quoted.push_str("\\f"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(c));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn format_line_around_position(line: &str, column_pos: usize) -> (String, usize) {
+    const MAX_ERROR_LINE_CHARS: usize = 80;
+
+    let chars: Vec<char> = line.chars().collect();
+    let max_context = MAX_ERROR_LINE_CHARS / 2;
+
+    let error_pos = column_pos.saturating_sub(1).min(chars.len());
+    let start_pos = error_pos.saturating_sub(max_context);
+    let end_pos = (error_pos + max_context + 1).min(chars.len());
+
+    let mut result = String::new();
+    let mut new_column_pos = error_pos - start_pos + 1;
+
+    if start_pos > 0 {
+        result.push_str("...");
+        new_column_pos += 3;
+    }
+
+    result.push_str(&chars[start_pos..end_pos].iter().collect::<String>());
+
+    if end_pos < chars.len() {
+        result.push_str("...");
+    }
+
+    (result, new_column_pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(text: &str) -> String {
+        format_with_options(text, &FormatOptions::default())
+    }
+
+    fn format_with_options(text: &str, options: &FormatOptions) -> String {
+        let (json, comment_ranges) = nojson::RawJson::parse_jsonc(text).expect("bug");
+        let mut buf = String::new();
+        let mut formatter = Formatter::with_options(text, comment_ranges, &mut buf, options);
+        formatter.format(json.value()).expect("bug");
+        buf
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(format(" null  "), "null\n");
+        assert_eq!(format(" \t\n false\n\n  "), "false\n");
+        assert_eq!(format(" 1\n "), "1\n");
+        assert_eq!(format(" \n\"foo\" "), "\"foo\"\n");
+    }
+
+    #[test]
+    fn empty_containers() {
+        assert_eq!(format("[]"), "[]\n");
+        assert_eq!(format("{}"), "{}\n");
+        assert_eq!(format(" [ ] "), "[]\n");
+        assert_eq!(format(" { } "), "{}\n");
+        assert_eq!(format("[\n]"), "[]\n");
+        assert_eq!(format("{\n\n}"), "{}\n");
+        assert_eq!(format("[ // c\n]"), "[ // c\n]\n");
+    }
+
+    #[test]
+    fn empty_containers_in_layout_modes() {
+        let text = "{\"a\": [ ], \"b\": {\n},\n\"c\": [[\n], { }]}";
+        let expected = "{\n  \"a\": [],\n  \"b\": {},\n  \"c\": [\n    [],\n    {}\n  ]\n}\n";
+        let all_options = [
+            FormatOptions::default(),
+            FormatOptions {
+                max_width: Some(1),
+                ..Default::default()
+            },
+            FormatOptions {
+                collapse_width: Some(1),
+                ..Default::default()
+            },
+            FormatOptions {
+                array_rows_threshold: Some(1),
+                object_rows_threshold: Some(1),
+                ..Default::default()
+            },
+            FormatOptions {
+                sort_keys: Some(KeyOrder::Lexical),
+                ..Default::default()
+            },
+            FormatOptions {
+                comment_align_to_value: true,
+                ..Default::default()
+            },
+            FormatOptions {
+                strip: true,
+                ..Default::default()
+            },
+        ];
+        for options in &all_options {
+            assert_eq!(format_with_options(text, options), expected, "{options:?}");
+        }
+
+        // Only the brackets of non-empty containers move.
+        let options = FormatOptions {
+            hug_closing_brackets: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(text, &options),
+            "{\n  \"a\": [],\n  \"b\": {},\n  \"c\": [\n    [],\n    {}\n  ]}\n"
+        );
+        let options = FormatOptions {
+            closing_bracket_indent: ClosingBracketIndent::MatchContent,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(text, &options),
+            "{\n  \"a\": [],\n  \"b\": {},\n  \"c\": [\n    [],\n    {}\n    ]\n  }\n"
+        );
+
+        // Directives and stripped comments don't expand them either.
+        assert_eq!(
+            format("[/* jcfmt:expand */ [ ]]"),
+            "[ /* jcfmt:expand */\n  []\n]\n"
+        );
+        let options = FormatOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(format_with_options("[ // c\n]", &options), "[]\n");
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(format("[1, 2, 3]"), "[1, 2, 3]\n");
+        assert_eq!(format("[1,2,3]"), "[1, 2, 3]\n");
+        assert_eq!(format("[ 1 , 2 , 3 ]"), "[1, 2, 3]\n");
+
+        // Multiline arrays
+        assert_eq!(format("[\n  1,\n  2,\n  3\n]"), "[\n  1,\n  2,\n  3\n]\n");
+
+        // Nested arrays
+        assert_eq!(format("[[1, 2], [3, 4]]"), "[[1, 2], [3, 4]]\n");
+        assert_eq!(
+            format("[\n  [1, 2],\n  [3, 4]\n]"),
+            "[\n  [1, 2],\n  [3, 4]\n]\n"
+        );
+    }
+
+    #[test]
+    fn objects() {
+        assert_eq!(format("{\"a\": 1}"), "{\"a\": 1}\n");
+        assert_eq!(format("{\"a\":1}"), "{\"a\": 1}\n");
+        assert_eq!(format("{ \"a\" : 1 }"), "{\"a\": 1}\n");
+
+        // Multiple properties
+        assert_eq!(format("{\"a\": 1, \"b\": 2}"), "{\"a\": 1, \"b\": 2}\n");
+
+        // Multiline objects
+        assert_eq!(
+            format("{\n  \"a\": 1,\n  \"b\": 2\n}"),
+            "{\n  \"a\": 1,\n  \"b\": 2\n}\n"
+        );
+
+        // Nested objects
+        assert_eq!(
+            format("{\"outer\": {\"inner\": 42}}"),
+            "{\"outer\": {\"inner\": 42}}\n"
+        );
+    }
+
+    #[test]
+    fn mixed_structures() {
+        assert_eq!(
+            format("{\"array\": [1, 2, 3], \"object\": {\"nested\": true}}"),
+            "{\"array\": [1, 2, 3], \"object\": {\"nested\": true}}\n"
+        );
+
+        assert_eq!(
+            format("[{\"a\": 1}, {\"b\": 2}]"),
+            "[{\"a\": 1}, {\"b\": 2}]\n"
+        );
+    }
+
+    #[test]
+    fn indentation() {
+        let input = r#"{
+"level1": {
+"level2": {
+"level3": "value"
+}
+}
+}"#;
+        let expected = r#"{
+  "level1": {
+    "level2": {
+      "level3": "value"
+    }
+  }
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn comments_single_line() {
+        let input = r#"{
+  "key": "value" // This is a comment
+}"#;
+        let expected = r#"{
+  "key": "value" // This is a comment
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn comments_multi_line() {
+        let input = r#"{
+  /* This is a
+     multi-line comment */
+  "key": "value"
+}"#;
+        let expected = r#"{
+  /* This is a
+     multi-line comment */
+  "key": "value"
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn comments_leading() {
+        let input = r#"// Leading comment
+{
+  "key": "value"
+}"#;
+        let expected = r#"// Leading comment
+{
+  "key": "value"
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn comments_mixed() {
+        let input = r#"{
+  // Comment before key
+  "key1": "value1", // Trailing comment
+  /* Block comment */
+  "key2": "value2"
+}"#;
+        let expected = r#"{
+  // Comment before key
+  "key1": "value1", // Trailing comment
+  /* Block comment */
+  "key2": "value2"
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn comments_trailing_last_element() {
+        assert_eq!(format("[\n 1,\n 2 // last\n]"), "[\n  1,\n  2 // last\n]\n");
+        assert_eq!(
+            format("[\n 1,\n 2 /* last */\n]"),
+            "[\n  1,\n  2 /* last */\n]\n"
+        );
+        assert_eq!(
+            format("{\"a\": [\n 1,\n 2 // last\n]}"),
+            "{\n  \"a\": [\n    1,\n    2 // last\n  ]\n}\n"
+        );
+
+        // A comment on its own line before the closing bracket stays there.
+        assert_eq!(
+            format("[\n 1,\n 2\n // end\n]"),
+            "[\n  1,\n  2\n  // end\n]\n"
+        );
+    }
+
+    #[test]
+    fn various_json_types() {
+        let input = r#"{
+  "null": null,
+  "boolean_true": true,
+  "boolean_false": false,
+  "integer": 42,
+  "float": 3.14,
+  "string": "hello world",
+  "empty_string": "",
+  "array": [],
+  "object": {}
+}"#;
+        let expected = r#"{
+  "null": null,
+  "boolean_true": true,
+  "boolean_false": false,
+  "integer": 42,
+  "float": 3.14,
+  "string": "hello world",
+  "empty_string": "",
+  "array": [],
+  "object": {}
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn whitespace_normalization() {
+        // Test excessive whitespace removal
+        let input = r#"{
+
+
+  "key"   :    "value"   ,
+
+
+  "another"  :   42
+
+
+}"#;
+        let expected = r#"{
+
+  "key": "value",
+
+  "another": 42
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn trailing_commas() {
+        // Test trailing comma in array
+        let input = r#"[
+  1,
+  2,
+  3,
+]"#;
+        let expected = r#"[
+  1,
+  2,
+  3,
+]
+"#;
+        assert_eq!(format(input), expected);
+
+        let input = r#"[1,2,3,]"#;
+        let expected = r#"[1, 2, 3,]
+"#;
+        assert_eq!(format(input), expected);
+        // Test trailing comma in object
+        let input = r#"{
+  "key1": "value1",
+  "key2": "value2",
+}"#;
+        let expected = r#"{
+  "key1": "value1",
+  "key2": "value2",
+}
+"#;
+        assert_eq!(format(input), expected);
+
+        // Test trailing comma with comments
+        let input = r#"{
+  "key1": "value1", // Comment after value
+  "key2": "value2", // Another comment
+  // Final comment before trailing comma
+}"#;
+        let expected = r#"{
+  "key1": "value1", // Comment after value
+  "key2": "value2", // Another comment
+  // Final comment before trailing comma
+}
+"#;
+        assert_eq!(format(input), expected);
+
+        // Test nested structures with trailing commas
+        let input = r#"{
+  "array": [
+    1,
+    2,
+  ],
+  "object": {
+    "nested": true,
+  },
+}"#;
+        let expected = r#"{
+  "array": [
+    1,
+    2,
+  ],
+  "object": {
+    "nested": true,
+  },
+}
+"#;
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn bytes_input() {
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_bytes(b"{\"a\":[1,2]}", &options).expect("bug"),
+            b"{\"a\": [1, 2]}\n"
+        );
+        assert_eq!(
+            format_bytes("[\"\u{3042}\"]".as_bytes(), &options).expect("bug"),
+            "[\"\u{3042}\"]\n".as_bytes()
+        );
+
+        let error = format_bytes(b"[\"\xff\"]", &options).expect_err("bug");
+        assert!(matches!(error, FormatError::InvalidUtf8(e) if e.valid_up_to() == 2));
+    }
+
+    #[test]
+    fn trailing_newline_if_missing_only() {
+        let options = FormatOptions {
+            trailing_newline_if_missing_only: true,
+            ..Default::default()
+        };
+        assert_eq!(format_with_options("[1,2]", &options), "[1, 2]\n");
+        assert_eq!(format_with_options("[1,2]\n", &options), "[1, 2]\n");
+        assert_eq!(format_with_options("[1,2]\n\n", &options), "[1, 2]\n\n");
+        assert_eq!(
+            format_with_options("[1,2] // foo\n\n", &options),
+            "[1, 2] // foo\n\n"
+        );
+
+        // Without the option, trailing newlines are normalized to exactly one.
+        assert_eq!(format("[1,2]\n\n"), "[1, 2]\n");
+    }
+
+    #[test]
+    fn line_endings() {
+        let options = FormatOptions::default();
+
+        // LF input keeps LF line endings.
+        let input = "{\"a\":1, // foo\n  /* bar\n     baz */\n\"b\":[1,2]}\n";
+        let expected = "{\n  \"a\": 1, // foo\n  /* bar\n     baz */\n  \"b\": [1, 2]\n}\n";
+        assert_eq!(format_text(input, &options).expect("bug"), expected);
+
+        // CRLF input keeps CRLF line endings.
+        let input = input.replace('\n', "\r\n");
+        let expected = expected.replace('\n', "\r\n");
+        assert_eq!(format_text(&input, &options).expect("bug"), expected);
+
+        // Single-line input without any line ending is emitted with LF.
+        assert_eq!(format_text("[1,2]", &options).expect("bug"), "[1, 2]\n");
+    }
+
+    #[test]
+    fn comment_align_to_value() {
+        let options = FormatOptions {
+            comment_align_to_value: true,
+            ..Default::default()
+        };
+        let input = r#"{
+  "a": 1, // one
+  "long_key": "value", /* two */
+  "nested": {"x": [1, 2]}, // three
+  "none": null,
+  "object": {
+    "b": true, // four
+    "cc": false // five
+  } // six
+}"#;
+        let expected = r#"{
+  "a": 1,                  // one
+  "long_key": "value",     /* two */
+  "nested": {"x": [1, 2]}, // three
+  "none": null,
+  "object": {
+    "b": true,  // four
+    "cc": false // five
+  }                        // six
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+
+        // Without the option, trailing comments follow their values with a single space.
+        assert_eq!(
+            format("{\n\"a\": 1, // one\n\"bb\": 2 // two\n}"),
+            "{\n  \"a\": 1, // one\n  \"bb\": 2 // two\n}\n"
+        );
+    }
+
+    #[test]
+    fn parse_only_report() {
+        let options = FormatOptions::default();
+        let diagnostics = parse_diagnostics(b"{\"a\": 1, // foo\n}", &options);
+        assert!(diagnostics.is_empty());
+        assert_eq!(nojson::Json(&diagnostics).to_string(), "[]");
+
+        let diagnostics = parse_diagnostics(b"{\n  \"a\": 1,\n  \"b\" 2\n}", &options);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (3, 7));
+        assert_eq!(
+            nojson::Json(&diagnostics).to_string(),
+            format!(
+                r#"[{{"severity":"error","line":3,"column":7,"message":"{}"}}]"#,
+                diagnostics[0].message
+            )
+        );
+
+        let diagnostics = parse_diagnostics(b"[\n  \"\xff\"]", &options);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (2, 4));
+
+        // The options that the formatter accepts the input with are applied.
+        for (input, options) in [
+            (
+                "{\n # c\n \"a\":1\n}",
+                FormatOptions {
+                    allow_hash_comments: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "{\"a\": 1}\ntrailer",
+                FormatOptions {
+                    trailing_content: Some(TrailingContent::Preserve),
+                    ..Default::default()
+                },
+            ),
+            (
+                "// only a comment\n",
+                FormatOptions {
+                    allow_comment_only: true,
+                    ..Default::default()
+                },
+            ),
+        ] {
+            assert!(format_text(input, &options).is_ok());
+            assert!(parse_diagnostics(input.as_bytes(), &options).is_empty());
+            assert_eq!(
+                parse_diagnostics(input.as_bytes(), &FormatOptions::default()).len(),
+                1
+            );
+        }
+
+        // Errors before trailing content are still reported.
+        let options = FormatOptions {
+            trailing_content: Some(TrailingContent::Drop),
+            ..Default::default()
+        };
+        let diagnostics = parse_diagnostics(b"{\"a\" 1}\ntrailer", &options);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (1, 6));
+    }
+
+    #[test]
+    fn strip_comments_to_blank_lines() {
+        let options = FormatOptions {
+            strip: true,
+            strip_comments_to_blank_lines: true,
+            ..Default::default()
+        };
+        let input = r#"// header
+{
+  // leading
+  "a": 1, // trailing
+  /* multi
+     line */
+  "b": [
+    2, /* inline */
+    3,
+  ]
+}
+// footer"#;
+        let expected = r#"
+{
+
+  "a": 1,
+
+
+  "b": [
+    2,
+    3
+  ]
+}
+
+"#;
+        let output = format_with_options(input, &options);
+        assert_eq!(output, expected);
+        assert_eq!(output.lines().count(), input.lines().count());
+
+        // The layout is the same as with plain stripping, and lines that hold something other
+        // than comments don't turn into blank lines.
+        for (input, expected) in [
+            (r#"{"a": 1, /* x */ "b": 2}"#, "{\"a\": 1, \"b\": 2}\n"),
+            (
+                "{\n  \"a\": 1,\n  /* x */ \"b\": 2\n}",
+                "{\n  \"a\": 1,\n  \"b\": 2\n}\n",
+            ),
+            (
+                "{\n  \"a\": 1, /* x\n y */\n  \"b\": 2\n}",
+                "{\n  \"a\": 1,\n\n  \"b\": 2\n}\n",
+            ),
+        ] {
+            let output = format_with_options(input, &options);
+            assert_eq!(output, expected);
+            assert_eq!(output.lines().count(), input.lines().count());
+        }
+
+        // Plain stripping collapses the gaps left by the removed comments.
+        let options = FormatOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(input, &options),
+            "{\n\n  \"a\": 1,\n\n  \"b\": [\n    2,\n    3\n  ]\n}\n"
+        );
+    }
+
+    #[test]
+    fn changed_only() {
+        let input = r#"{
+  "a": {"x":1,  "y":[1,2]},
+  "b": {"x":1,  "y":[1,2]},
+  "c": [
+     3,4, // four
+  5],
+  "d": [5,6]
+}
+"#;
+
+        // Only the members and elements on the changed lines are reformatted.
+        let options = FormatOptions {
+            changed_lines: Some(vec![2..=2, 4..=6]),
+            ..Default::default()
+        };
+        let expected = r#"{
+  "a": {"x": 1, "y": [1, 2]},
+  "b": {"x":1,  "y":[1,2]},
+  "c": [
+    3,
+    4, // four
+    5
+  ],
+  "d": [5,6]
+}
+"#;
+        assert_eq!(format_text(input, &options).expect("bug"), expected);
+
+        // Partially changed containers are not reformatted, but their changed children are.
+        let options = FormatOptions {
+            changed_lines: Some(vec![3..=3]),
+            ..Default::default()
+        };
+        let expected = input.replace(
+            r#""b": {"x":1,  "y":[1,2]}"#,
+            r#""b": {"x": 1, "y": [1, 2]}"#,
+        );
+        assert_eq!(format_text(input, &options).expect("bug"), expected);
+
+        // Keys and indentation on the changed lines are normalized too.
+        let options = FormatOptions {
+            changed_lines: Some(vec![3..=4]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text(
+                "{\n  \"a\":1,\n  \"b\"   :   [1,2],\n    \"c\":3\n}\n",
+                &options
+            )
+            .expect("bug"),
+            "{\n  \"a\":1,\n  \"b\": [1, 2],\n  \"c\": 3\n}\n"
+        );
+
+        // A member with a changed first line is reformatted as a whole.
+        let options = FormatOptions {
+            changed_lines: Some(vec![3..=3]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text(
+                "{\n  \"a\":1,\n  \"b\" : [1,\n2],\n    \"c\":3\n}\n",
+                &options
+            )
+            .expect("bug"),
+            "{\n  \"a\":1,\n  \"b\": [\n    1,\n    2\n  ],\n    \"c\":3\n}\n"
+        );
+
+        // If the root value is changed entirely, the whole document is reformatted.
+        let options = FormatOptions {
+            changed_lines: Some(vec![1..=100]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text(input, &options).expect("bug"),
+            format_text(input, &FormatOptions::default()).expect("bug")
+        );
+
+        // CRLF line endings are kept both inside and outside the changed lines.
+        let options = FormatOptions {
+            changed_lines: Some(vec![3..=4]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text("{\r\n  \"a\":1,\r\n  \"b\": [1,\r\n 2]\r\n}\r\n", &options).expect("bug"),
+            "{\r\n  \"a\":1,\r\n  \"b\": [\r\n    1,\r\n    2\r\n  ]\r\n}\r\n"
+        );
+        let options = FormatOptions {
+            changed_lines: Some(vec![1..=5]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text("{\r\n  \"a\":1\r\n}\r\n", &options).expect("bug"),
+            "{\r\n  \"a\": 1\r\n}\r\n"
+        );
+    }
+
+    #[test]
+    fn max_width() {
+        let options = FormatOptions {
+            max_width: Some(20),
+            ..Default::default()
+        };
+        let input = r#"{"a": [1, 2, 3], "b": {"c": "long value"}}"#;
+        let expected = r#"{
+  "a": [1, 2, 3],
+  "b": {
+    "c": "long value"
+  }
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+        assert_eq!(format_with_options("[1, 2]", &options), "[1, 2]\n");
+        assert_eq!(format_with_options("[[], {}]", &options), "[[], {}]\n");
+        assert_eq!(
+            format_with_options("{\"long_key\": [ ]}", &options),
+            "{\"long_key\": []}\n"
+        );
+    }
+
+    #[test]
+    fn max_width_ignore_comments() {
+        let input = r#"{
+  "a": [1, 2, 3], // a long trailing comment
+  "b": [4, 5]
+}"#;
+
+        // By default, the trailing comment counts towards the line width.
+        let options = FormatOptions {
+            max_width: Some(20),
+            ..Default::default()
+        };
+        let expected = r#"{
+  "a": [
+    1,
+    2,
+    3
+  ], // a long trailing comment
+  "b": [4, 5]
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+
+        let options = FormatOptions {
+            max_width: Some(20),
+            max_width_ignore_comments: true,
+            ..Default::default()
+        };
+        let expected = r#"{
+  "a": [1, 2, 3], // a long trailing comment
+  "b": [4, 5]
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+    }
+
+    #[test]
+    fn indent_spec_decreasing() {
+        let options = FormatOptions {
+            indent_spec: Some(vec![4, 2]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text("[[[[1,\n2]]]]", &options).expect("bug"),
+            "[\n    [\n  [\n  [\n  1,\n  2\n  ]\n  ]\n    ]\n]\n"
+        );
+    }
+
+    #[test]
+    fn indent_spec() {
+        let options = FormatOptions {
+            indent_spec: Some(vec![4, 6, 10]),
+            ..Default::default()
+        };
+        assert_eq!(
+            (0..6)
+                .map(|level| options.indent_width(level))
+                .collect::<Vec<_>>(),
+            [0, 4, 6, 10, 14, 18]
+        );
+
+        let input = r#"{
+"a": {
+"b": [
+    /* c
+       d */
+1
+]}}"#;
+        let expected = r#"{
+    "a": {
+      "b": [
+          /* c
+             d */
+          1
+      ]
+    }
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+    }
+
+    // A small, deterministic PRNG (xorshift64*) for generating random JSONC documents.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % n
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+    }
+
+    fn generate_space(rng: &mut Rng, out: &mut String) {
+        let space = rng.pick(&[
+            "",
+            "",
+            "",
+            " ",
+            "\n",
+            "  \n\n  ",
+            "\t",
+            "// a, ] } \"x\"\n",
+            "/* b */",
+            "/* c\n   ] */",
+            " // d\n\n",
+            "/* jcfmt:expand */",
+            "/* jcfmt:collapse */ ",
+        ]);
+        out.push_str(space);
+    }
+
+    fn generate_value(rng: &mut Rng, depth: usize, out: &mut String) {
+        let kinds = if depth > 3 { 5 } else { 8 };
+        match rng.below(kinds) {
+            0 => out.push_str(rng.pick(&["null", "true", "false"])),
+            1 => out.push_str(rng.pick(&["0", "-12", "3.5", "1e10", "-0.25E-3"])),
+            2..=4 => {
+                out.push('"');
+                for _ in 0..rng.below(4) {
+                    let piece = rng.pick(&[
+                        "a", ",", "]", "}", ":", "[", "{", "//", "/*", "*/", "\\\"", "\\n", " ",
+                        "\u{3042}",
+                    ]);
+                    out.push_str(piece);
+                }
+                out.push('"');
+            }
+            5 | 6 => {
+                out.push('[');
+                let n = rng.below(4);
+                for i in 0..n {
+                    generate_space(rng, out);
+                    generate_value(rng, depth + 1, out);
+                    generate_space(rng, out);
+                    if i + 1 < n || rng.below(3) == 0 {
+                        out.push(',');
+                    }
+                }
+                generate_space(rng, out);
+                out.push(']');
+            }
+            _ => {
+                out.push('{');
+                let n = rng.below(4);
+                for i in 0..n {
+                    generate_space(rng, out);
+                    out.push_str(&format!("\"k{i}\""));
+                    generate_space(rng, out);
+                    out.push(':');
+                    generate_space(rng, out);
+                    generate_value(rng, depth + 1, out);
+                    generate_space(rng, out);
+                    if i + 1 < n || rng.below(3) == 0 {
+                        out.push(',');
+                    }
+                }
+                generate_space(rng, out);
+                out.push('}');
+            }
+        }
+    }
+
+    fn structure(value: nojson::RawJsonValue<'_, '_>) -> String {
+        match value.kind() {
+            nojson::JsonValueKind::Array => {
+                let elements = value.to_array().expect("bug").map(structure);
+                format!("[{}]", elements.collect::<Vec<_>>().join(","))
+            }
+            nojson::JsonValueKind::Object => {
+                let members = value
+                    .to_object()
+                    .expect("bug")
+                    .map(|(k, v)| format!("{}:{}", k.as_raw_str(), structure(v)));
+                format!("{{{}}}", members.collect::<Vec<_>>().join(","))
+            }
+            _ => value.as_raw_str().to_owned(),
+        }
+    }
+
+    fn check_roundtrip(input: &str) {
+        let (json, _) = nojson::RawJson::parse_jsonc(input).expect("bug");
+        for (name, options) in selfcheck_option_sets() {
+            let output = format_with_options(input, &options);
+            if let Err(e) = check_formatting(input, &options) {
+                panic!("{name}: {e}\nINPUT:\n{input}\nOUTPUT:\n{output}");
+            }
+
+            // Unless sorting, the members also keep their order and scalars their text.
+            if options.sort_keys.is_none() {
+                let (formatted, _) = nojson::RawJson::parse_jsonc(&output).expect("bug");
+                assert_eq!(
+                    structure(json.value()),
+                    structure(formatted.value()),
+                    "{name}\nINPUT:\n{input}\nOUTPUT:\n{output}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_seed_corpus() {
+        for (_, seed) in SELFCHECK_FIXTURES {
+            check_roundtrip(seed);
+        }
+    }
+
+    #[test]
+    fn selfcheck_passes() {
+        let results = selfcheck();
+        assert_eq!(results.len(), SELFCHECK_FIXTURES.len() * 4);
+        for result in results {
+            assert_eq!(result.error, None, "{result:?}");
+        }
+        assert_eq!(
+            check_formatting("[1, // one\n2]", &FormatOptions::default()),
+            Ok(())
+        );
+        let options = FormatOptions {
+            preview_array_limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_formatting("[1, 2]", &options),
+            Err("the values changed".to_owned())
+        );
+    }
+
+    #[test]
+    fn roundtrip_random_documents() {
+        let iterations = std::env::var("JCFMT_FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(2000);
+        let mut rng = Rng(0x5eed);
+        for _ in 0..iterations {
+            let mut input = String::new();
+            generate_space(&mut rng, &mut input);
+            generate_value(&mut rng, 0, &mut input);
+            generate_space(&mut rng, &mut input);
+            check_roundtrip(&input);
+        }
+    }
+
+    #[test]
+    fn sort_keys() {
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(r#"{"b": 1, "a": {"d": 2, "c": 3}, "\u0030": 0}"#, &options),
+            "{\"\\u0030\": 0, \"a\": {\"c\": 3, \"d\": 2}, \"b\": 1}\n"
+        );
+
+        // Leading and trailing comments move with their members.
+        let input = r#"{
+  // about b
+  "b": 1, // b
+  "a": 2 /* a */
+  // footer
+}"#;
+        let expected = r#"{
+  "a": 2, /* a */
+  // about b
+  "b": 1 // b
+  // footer
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+
+        // Comments on the line of `{` stay there.
+        assert_eq!(
+            format_with_options("{ // lead\n\"b\": 1, \"a\": 2}", &options),
+            "{ // lead\n  \"a\": 2,\n  \"b\": 1\n}\n"
+        );
+        assert_eq!(
+            format_with_options("{ /* lead */ \"b\": 1, \"a\": 2}", &options),
+            "{ /* lead */\n  \"a\": 2,\n  \"b\": 1\n}\n"
+        );
+
+        // A block comment containing `//` does not swallow the comment after the comma.
+        assert_eq!(
+            format_with_options("{\"b\": 1 /* http://x */, /* after */\n\"a\": 2}", &options),
+            "{\n  \"a\": 2,\n  \"b\": 1 /* http://x */ /* after */\n}\n"
+        );
+
+        // Trailing comments are aligned after sorting.
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            comment_align_to_value: true,
+            ..Default::default()
+        };
+        let input = "{\"bb\": 1, // x\n\"a\": [1,\n2], // y\n\"c\": 3 // z\n}";
+        let expected = r#"{
+  "a": [
+    1,
+    2
+  ],       // y
+  "bb": 1, // x
+  "c": 3   // z
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+    }
+
+    #[test]
+    fn key_sort_numeric_first() {
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::NumericFirst),
+            ..Default::default()
+        };
+        let input = r#"{"name": 0, "10": 1, "9": 2, "id": 3, "010": 4, "1.5": 5, "2": 6}"#;
+        let expected = r#"{"2": 6, "9": 2, "010": 4, "10": 1, "1.5": 5, "id": 3, "name": 0}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+
+        // Plain lexical order for comparison.
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            ..Default::default()
+        };
+        let expected = r#"{"010": 4, "1.5": 5, "10": 1, "2": 6, "9": 2, "id": 3, "name": 0}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+    }
+
+    #[test]
+    fn sort_keys_deterministic() {
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(
+                r#"{"😀": 0, "é": 1, "a": 2, "\uffff": 3, "z": 4, "B": 5}"#,
+                &options
+            ),
+            "{\"B\": 5, \"a\": 2, \"z\": 4, \"é\": 1, \"\\uffff\": 3, \"😀\": 0}\n"
+        );
+
+        // Any input order of the same keys gives the same output, in code point order.
+        let mut rng = Rng(0x5047);
+        let pieces = [
+            "a", "B", "0", "10", "é", "\\u00e9x", "😀", "\\uffff", "_", " ",
+        ];
+        for _ in 0..200 {
+            let mut keys = (0..rng.below(6) + 1)
+                .map(|_| {
+                    let n = rng.below(3) + 1;
+                    (0..n).map(|_| rng.pick(&pieces)).collect::<String>()
+                })
+                .collect::<Vec<_>>();
+            // Duplicate keys would keep their (shuffled) input order.
+            let mut unescaped_keys = std::collections::BTreeSet::new();
+            keys.retain(|k| unescaped_keys.insert(k.replace("\\u00e9", "é")));
+
+            for order in [KeyOrder::Lexical, KeyOrder::NumericFirst] {
+                let options = FormatOptions {
+                    sort_keys: Some(order),
+                    ..Default::default()
+                };
+                let mut outputs = Vec::new();
+                for _ in 0..4 {
+                    for i in (1..keys.len()).rev() {
+                        keys.swap(i, rng.below(i + 1));
+                    }
+                    let members = keys.iter().map(|k| format!("\"{k}\": 0"));
+                    let input = format!("{{{}}}", members.collect::<Vec<_>>().join(", "));
+                    outputs.push(format_with_options(&input, &options));
+                }
+                assert!(outputs.windows(2).all(|w| w[0] == w[1]), "{outputs:?}");
+
+                if order == KeyOrder::Lexical {
+                    let json = nojson::RawJson::parse(&outputs[0]).expect("bug");
+                    let sorted_keys = json
+                        .value()
+                        .to_object()
+                        .expect("bug")
+                        .map(|(k, _)| k.to_unquoted_string_str().expect("bug").into_owned())
+                        .collect::<Vec<_>>();
+                    assert!(
+                        sorted_keys
+                            .windows(2)
+                            .all(|w| w[0].chars().lt(w[1].chars())),
+                        "{sorted_keys:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sort_keys_at() {
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            sort_keys_at: Some(vec!["/b/0".to_owned(), "/c~1d".to_owned()]),
+            ..Default::default()
+        };
+        let text =
+            r#"{"z": 0, "b": [{"y": 1, "x": 2}, {"y": 3, "x": 4}], "c/d": {"q": 5, "p": 6}}"#;
+        assert_eq!(
+            format_with_options(text, &options),
+            "{\"z\": 0, \"b\": [{\"x\": 2, \"y\": 1}, {\"y\": 3, \"x\": 4}], \"c/d\": {\"p\": 6, \"q\": 5}}\n"
+        );
+
+        // An empty pointer refers to the root value.
+        let options = FormatOptions {
+            sort_keys_at: Some(vec![String::new()]),
+            ..options
+        };
+        assert_eq!(
+            format_with_options(r#"{"b": {"d": 1, "c": 2}, "a": 3}"#, &options),
+            "{\"a\": 3, \"b\": {\"d\": 1, \"c\": 2}}\n"
+        );
+
+        // The paths of fragments formatted with `changed_lines` start from the root value.
+        let options = FormatOptions {
+            sort_keys_at: Some(vec!["/a/b".to_owned()]),
+            changed_lines: Some(vec![2..=2]),
+            ..options
+        };
+        assert_eq!(
+            format_text(
+                "{\"a\": {\n\"b\": {\"y\":1,\"x\":2}, \"c\": {\"y\":1,\"x\":2}\n}}",
+                &options
+            )
+            .expect("bug"),
+            "{\"a\": {\n    \"b\": {\"x\": 2, \"y\": 1}, \"c\": {\"y\": 1, \"x\": 2}\n}}"
+        );
+    }
+
+    #[test]
+    fn sort_keys_at_paths() {
+        let sort_at = |pointer: &str| FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            sort_keys_at: Some(vec![pointer.to_owned()]),
+            ..Default::default()
+        };
+        let text = r#"{"\u0061": [1, {"b/c": {"~": {"y": 1, "x": 2}}}], "d": {"y": 1, "x": 2}}"#;
+
+        // Keys are unescaped before being compared with the pointer's tokens.
+        assert_eq!(
+            format_with_options(text, &sort_at("/a/1/b~1c/~0")),
+            "{\"\\u0061\": [1, {\"b/c\": {\"~\": {\"x\": 2, \"y\": 1}}}], \"d\": {\"y\": 1, \"x\": 2}}\n"
+        );
+
+        // Array indices must be written without leading zeros, and the whole path must match.
+        for pointer in [
+            "/a/01/b~1c/~0",
+            "/a/1/b~1c",
+            "/a/1/b~1c/~0/y",
+            "/b/1/b~1c/~0",
+        ] {
+            assert_eq!(format_with_options(text, &sort_at(pointer)), format(text));
+        }
+
+        // Omitted elements don't shift the indices of the others.
+        let options = FormatOptions {
+            preview_array_limit: Some(2),
+            ..sort_at("/1")
+        };
+        assert_eq!(
+            format_with_options(r#"[{"b": 1, "a": 2}, {"b": 3, "a": 4}, {}]"#, &options),
+            "[{\"b\": 1, \"a\": 2}, {\"a\": 4, \"b\": 3}, /* ... 1 more */]\n"
+        );
+    }
+
+    #[test]
+    fn path_tracking() {
+        // Returns the JSON Pointer of each object in the document, with the number in the name
+        // of its first key (`"z<id>"`).
+        fn object_paths(
+            value: nojson::RawJsonValue<'_, '_>,
+            pointer: &str,
+        ) -> Vec<(String, String)> {
+            let mut paths = Vec::new();
+            match value.kind() {
+                nojson::JsonValueKind::Array => {
+                    for (i, element) in value.to_array().expect("bug").enumerate() {
+                        paths.extend(object_paths(element, &format!("{pointer}/{i}")));
+                    }
+                }
+                nojson::JsonValueKind::Object => {
+                    for (i, (key, value)) in value.to_object().expect("bug").enumerate() {
+                        let key = key.to_unquoted_string_str().expect("bug");
+                        if i == 0 {
+                            paths.push((pointer.to_owned(), key[1..].to_owned()));
+                        }
+                        let token = key.replace('~', "~0").replace('/', "~1");
+                        paths.extend(object_paths(value, &format!("{pointer}/{token}")));
+                    }
+                }
+                _ => {}
+            }
+            paths
+        }
+
+        // Sorts the objects at `pointers` and returns the ids of the objects that were sorted.
+        fn sorted_ids(text: &str, pointers: &[&str], options: &FormatOptions) -> Vec<String> {
+            let options = FormatOptions {
+                sort_keys: Some(KeyOrder::Lexical),
+                sort_keys_at: Some(pointers.iter().map(|&p| p.to_owned()).collect()),
+                ..options.clone()
+            };
+            let output = format_text(text, &options).expect("bug");
+            let (json, _) = nojson::RawJson::parse_jsonc(text).expect("bug");
+            object_paths(json.value(), "")
+                .into_iter()
+                .map(|(_, id)| id)
+                .filter(|id| {
+                    let position = |key: String| output.find(&format!("\"{key}\""));
+                    position(format!("a{id}")) < position(format!("z{id}"))
+                })
+                .collect()
+        }
+
+        let text = r#"{
+  "z0": 0,
+  "a0": [{"z1": 1, "a1": 1}, [{"z2": 2, "a2": 2}], {"z3": 3, "a3": 3}],
+  "b/~c": {"z4": {"z5": 5, "a5": 5}, "a4": 4}
+}"#;
+        let (json, _) = nojson::RawJson::parse_jsonc(text).expect("bug");
+        let paths = object_paths(json.value(), "");
+        assert_eq!(
+            paths.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>(),
+            ["", "/a0/0", "/a0/1/0", "/a0/2", "/b~1~0c", "/b~1~0c/z4"]
+        );
+
+        for (pointer, id) in &paths {
+            // In arrays and objects.
+            let options = FormatOptions::default();
+            assert_eq!(sorted_ids(text, &[pointer], &options), vec![id.clone()]);
+
+            // In the members of a sorted object.
+            let mut expected = vec!["0".to_owned(), id.clone()];
+            expected.dedup();
+            assert_eq!(sorted_ids(text, &["", pointer], &options), expected);
+
+            // In previewed arrays, whose omitted elements are not formatted.
+            let options = FormatOptions {
+                preview_array_limit: Some(2),
+                ..Default::default()
+            };
+            let expected = if id == "3" { vec![] } else { vec![id.clone()] };
+            assert_eq!(sorted_ids(text, &[pointer], &options), expected);
+        }
+
+        // In fragments formatted with `changed_lines`, starting from the root value.
+        let options = FormatOptions {
+            changed_lines: Some(vec![4..=4]),
+            ..Default::default()
+        };
+        for (pointer, id) in &paths {
+            let expected = if ["4", "5"].contains(&id.as_str()) {
+                vec![id.clone()]
+            } else {
+                vec![]
+            };
+            assert_eq!(sorted_ids(text, &[pointer], &options), expected);
+        }
+    }
+
+    #[test]
+    fn sort_keys_keep_comments_fixed() {
+        let input = r#"{
+  // Section 1
+  "b": 1, // b
+  "d": 2,
+
+  // Section 2
+  "a": 3,
+  "c": 4 // c
+  // footer
+}"#;
+
+        // By default, standalone comments move together with the following member.
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            ..Default::default()
+        };
+        let expected = r#"{
+
+  // Section 2
+  "a": 3,
+  // Section 1
+  "b": 1, // b
+  "c": 4, // c
+  "d": 2
+  // footer
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            sort_keys_keep_comments_fixed: true,
+            ..Default::default()
+        };
+        let expected = r#"{
+  // Section 1
+  "a": 3,
+  "b": 1, // b
+
+  // Section 2
+  "c": 4, // c
+  "d": 2
+  // footer
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+    }
+
+    #[test]
+    fn format_detailed_result() {
+        let options = FormatOptions {
+            max_width: Some(11),
+            ..Default::default()
+        };
+        let input = r#"{"a": 1, "long_key_name": {"b": 2, "b": 3}, "a": 4}"#;
+        let result = format_detailed(input, &options).expect("bug");
+        assert_eq!(
+            result.formatted,
+            r#"{
+  "a": 1,
+  "long_key_name": {
+    "b": 2,
+    "b": 3
+  },
+  "a": 4
+}
+"#
+        );
+        assert!(result.changed);
+        assert_eq!(
+            result.warnings,
+            [
+                Warning::DuplicateKey {
+                    key: "b".to_owned(),
+                    line: 1
+                },
+                Warning::DuplicateKey {
+                    key: "a".to_owned(),
+                    line: 1
+                },
+                Warning::LongLine { line: 3, width: 20 },
+            ]
+        );
+        assert_eq!(
+            result.warnings[2].to_string(),
+            "output line 3 is 20 characters wide"
+        );
+        assert_eq!(
+            Warning::LongLine { line: 1, width: 1 }.to_string(),
+            "output line 1 is 1 character wide"
+        );
+
+        let result = format_detailed(&result.formatted, &FormatOptions::default()).expect("bug");
+        assert!(!result.changed);
+    }
+
+    #[test]
+    fn no_trailing_space_after_colon() {
+        let input = r#"{
+  "a":
+  [1,
+  2],
+  "b": // comment
+  {"c": 1},
+  "d":
+  /* comment */
+  {
+    "e": [3]
+  }
+}"#;
+        let expected = r#"{
+  "a": [
+    1,
+    2
+  ],
+  "b": // comment
+  {"c": 1},
+  "d":
+  /* comment */
+  {
+    "e": [3]
+  }
+}
+"#;
+        assert_eq!(format(input), expected);
+
+        let options = FormatOptions {
+            strip: true,
+            strip_comments_to_blank_lines: true,
+            ..Default::default()
+        };
+        let output = format_with_options(input, &options);
+        let stripped = FormatOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(output, format_with_options(input, &stripped));
+        assert!(
+            output.lines().all(|line| line == line.trim_end()),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn post_colon_line_comment() {
+        // The comment stays on the key's line, and the value starts the next line.
+        assert_eq!(
+            format("{\"key\": // why\n 1, \"b\": 2}"),
+            "{\n  \"key\": // why\n  1,\n  \"b\": 2\n}\n"
+        );
+        assert_eq!(
+            format("{\"k\": /* a */ // b\n [1, 2]}"),
+            "{\n  \"k\": /* a */ // b\n  [1, 2]\n}\n"
+        );
+
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            comment_align_to_value: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("{\"z\": 0, \"key\":   // why\n [1,\n2]}", &options),
+            "{\n  \"key\": // why\n  [\n    1,\n    2\n  ],\n  \"z\": 0\n}\n"
+        );
+    }
+
+    #[test]
+    fn max_width_boundary() {
+        let format_with_width = |text: &str, max_width: usize| {
+            let options = FormatOptions {
+                max_width: Some(max_width),
+                ..Default::default()
+            };
+            format_with_options(text, &options)
+        };
+
+        // `[1, 2, 3]` is 9 characters wide.
+        assert_eq!(format_with_width("[1,2,3]", 8), "[\n  1,\n  2,\n  3\n]\n");
+        assert_eq!(format_with_width("[1,2,3]", 9), "[1, 2, 3]\n");
+        assert_eq!(format_with_width("[1,2,3]", 10), "[1, 2, 3]\n");
+
+        // `  "a": [1, 2],` is 14 characters wide including the comma.
+        let input = "{\n\"a\": [1, 2],\n\"b\": 0\n}";
+        assert_eq!(
+            format_with_width(input, 13),
+            "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": 0\n}\n"
+        );
+        assert_eq!(
+            format_with_width(input, 14),
+            "{\n  \"a\": [1, 2],\n  \"b\": 0\n}\n"
+        );
+        assert_eq!(
+            format_with_width(input, 15),
+            "{\n  \"a\": [1, 2],\n  \"b\": 0\n}\n"
+        );
+
+        // A comma on a later line is written right after the value, so it counts too.
+        let input = "{\n\"a\": [1, 2]\n,\n\"b\": 0\n}";
+        assert_eq!(
+            format_with_width(input, 13),
+            "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": 0\n}\n"
+        );
+        assert_eq!(
+            format_with_width(input, 14),
+            "{\n  \"a\": [1, 2],\n  \"b\": 0\n}\n"
+        );
+
+        // So does a comma after a block comment: `  "a": [1, 2] /* c */,` is 22 wide.
+        let input = "{\n\"a\": [1, 2] /* c */,\n\"b\": 0\n}";
+        assert_eq!(
+            format_with_width(input, 21),
+            "{\n  \"a\": [\n    1,\n    2\n  ] /* c */,\n  \"b\": 0\n}\n"
+        );
+        assert_eq!(
+            format_with_width(input, 22),
+            "{\n  \"a\": [1, 2] /* c */,\n  \"b\": 0\n}\n"
+        );
+    }
+
+    #[test]
+    fn preview_array_limit() {
+        let options = FormatOptions {
+            preview_array_limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[1, 2, 3, 4, 5]", &options),
+            "[1, 2, /* ... 3 more */]\n"
+        );
+        assert_eq!(format_with_options("[1, 2]", &options), "[1, 2]\n");
+        assert_eq!(
+            format_with_options("[[1, 2, 3], [4], [5]]", &options),
+            "[[1, 2, /* ... 1 more */], [4], /* ... 1 more */]\n"
+        );
+
+        let input = r#"{
+  "items": [
+    1, // one
+    2,
+    3, // three
+    4,
+  ]
+}"#;
+        let expected = r#"{
+  "items": [
+    1, // one
+    2,
+    /* ... 2 more */
+  ]
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+
+        let options = FormatOptions {
+            preview_array_limit: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[1, 2]", &options),
+            "[/* ... 2 more */]\n"
+        );
+    }
+
+    #[test]
+    fn normalize_escapes() {
+        let options = FormatOptions {
+            normalize_escapes: true,
+            ..Default::default()
+        };
+        let cases = [
+            (r#""\u0041""#, r#""A""#),
+            (r#""\u000A\u000d\u0009""#, r#""\n\r\t""#),
+            (r#""\u0008\u000C""#, r#""\b\f""#),
+            (r#""\u0022\u005C""#, r#""\"\\""#),
+            (r#""\/""#, r#""/""#),
+            (r#""\u0001\u001F""#, r#""\u0001\u001f""#),
+            (r#""\u00e9""#, "\"\u{e9}\""),
+            (r#""\u007f""#, "\"\u{7f}\""),
+            (r##""plain \"text\"""##, r##""plain \"text\"""##),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                format_with_options(input, &options),
+                format!("{expected}\n"),
+                "{input}"
+            );
+        }
+
+        assert_eq!(
+            format_with_options(r#"{"\u0041": "\u0042"}"#, &options),
+            "{\"A\": \"B\"}\n"
+        );
+        assert_eq!(format(r#""\u0041""#), "\"\\u0041\"\n");
+    }
+
+    #[test]
+    fn shebang() {
+        let options = FormatOptions::default();
+        let input = "#!/usr/bin/env jcfmt\n/* license */\n{\"a\":1}";
+        assert_eq!(
+            format_text(input, &options).expect("bug"),
+            "#!/usr/bin/env jcfmt\n/* license */\n{\"a\": 1}\n"
+        );
+        assert_eq!(
+            format_text("#!/usr/bin/env jcfmt\r\n[1,2]\r\n", &options).expect("bug"),
+            "#!/usr/bin/env jcfmt\r\n[1, 2]\r\n"
+        );
+
+        let detailed = format_detailed(input, &options).expect("bug");
+        assert!(
+            detailed
+                .formatted
+                .starts_with("#!/usr/bin/env jcfmt\n/* license */\n")
+        );
+        assert!(detailed.changed);
+
+        let options = FormatOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(format_text(input, &options).expect("bug"), "{\"a\": 1}\n");
+    }
+
+    #[test]
+    fn numbers_as_strings() {
+        let options = FormatOptions {
+            numbers_as_strings: true,
+            ..Default::default()
+        };
+        assert_eq!(format_with_options("123", &options), "\"123\"\n");
+        assert_eq!(
+            format_with_options("[-1, 12345678901234567890, 1.5e-3, 0.10]", &options),
+            "[\"-1\", \"12345678901234567890\", \"1.5e-3\", \"0.10\"]\n"
+        );
+        assert_eq!(
+            format_with_options(r#"{"1": "2", "a": [true, null, 3]}"#, &options),
+            "{\"1\": \"2\", \"a\": [true, null, \"3\"]}\n"
+        );
+
+        let options = FormatOptions {
+            numbers_as_strings: true,
+            max_width: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(format_with_options("[1, 2]", &options), "[\"1\", \"2\"]\n");
+        assert_eq!(
+            format_with_options("[1, 2, 3]", &options),
+            "[\n  \"1\",\n  \"2\",\n  \"3\"\n]\n"
+        );
+    }
+
+    #[test]
+    fn empty_to_null() {
+        let text = r#"{"": "", "a": [], "o": {}, "s": [" ", [0], {"k": false}]}"#;
+        let with = |empty_to_null| FormatOptions {
+            empty_to_null,
+            ..Default::default()
+        };
+        let strings = EmptyValues {
+            strings: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(text, &with(strings)),
+            "{\"\": null, \"a\": [], \"o\": {}, \"s\": [\" \", [0], {\"k\": false}]}\n"
+        );
+        let arrays = EmptyValues {
+            arrays: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(text, &with(arrays)),
+            "{\"\": \"\", \"a\": null, \"o\": {}, \"s\": [\" \", [0], {\"k\": false}]}\n"
+        );
+        let objects = EmptyValues {
+            objects: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(text, &with(objects)),
+            "{\"\": \"\", \"a\": [], \"o\": null, \"s\": [\" \", [0], {\"k\": false}]}\n"
+        );
+
+        // Empty containers with comments are kept.
+        let all = EmptyValues {
+            strings: true,
+            arrays: true,
+            objects: true,
+        };
+        assert_eq!(
+            format_with_options("[[], {}, \"\"]", &with(all)),
+            "[null, null, null]\n"
+        );
+        assert_eq!(
+            format_with_options("[[ // c\n], {\n}]", &with(all)),
+            "[\n  [ // c\n  ],\n  null\n]\n"
+        );
+    }
+
+    #[test]
+    fn array_rows_threshold() {
+        let options = FormatOptions {
+            array_rows_threshold: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[\n  1,\n  2,\n  3\n]", &options),
+            "[1, 2, 3]\n"
+        );
+        assert_eq!(
+            format_with_options("[1, 2, 3, 4, 5]", &options),
+            "[\n  1,\n  2,\n  3,\n  4,\n  5\n]\n"
+        );
+        assert_eq!(
+            format_with_options("[\n  1, // one\n  2\n]", &options),
+            "[\n  1, // one\n  2\n]\n"
+        );
+        assert_eq!(
+            format_with_options("[\n  [1, 2, 3, 4],\n  5\n]", &options),
+            "[\n  [\n    1,\n    2,\n    3,\n    4\n  ],\n  5\n]\n"
+        );
+        assert_eq!(
+            format_with_options("[\n  {\n    \"a\": 1\n  }\n]", &options),
+            "[\n  {\n    \"a\": 1\n  }\n]\n"
+        );
+        assert_eq!(format_with_options("[\n]", &options), "[]\n");
+
+        // Objects around an expanded array are expanded as well.
+        assert_eq!(
+            format_with_options("[{\"a\": [1, 2, 3, 4]}]", &options),
+            "[\n  {\n    \"a\": [\n      1,\n      2,\n      3,\n      4\n    ]\n  }\n]\n"
+        );
+        let expected =
+            "{\n  \"a\": [\n    1,\n    2,\n    3,\n    4\n  ],\n  \"b\": {\"c\": [5]}\n}\n";
+        assert_eq!(
+            format_with_options(r#"{"a": [1, 2, 3, 4], "b": {"c": [5]}}"#, &options),
+            expected
+        );
+        assert_eq!(format_with_options(expected, &options), expected);
+
+        let options = FormatOptions {
+            array_rows_threshold: Some(4),
+            max_width: Some(8),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[\n  10,\n  20,\n  30\n]", &options),
+            "[\n  10,\n  20,\n  30\n]\n"
+        );
+    }
+
+    #[test]
+    fn object_rows_threshold() {
+        let options = FormatOptions {
+            object_rows_threshold: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("{\n  \"a\": 1,\n  \"b\": 2\n}", &options),
+            "{\"a\": 1, \"b\": 2}\n"
+        );
+        assert_eq!(
+            format_with_options(r#"{"a": 1, "b": 2, "c": 3, "d": 4}"#, &options),
+            "{\n  \"a\": 1,\n  \"b\": 2,\n  \"c\": 3,\n  \"d\": 4\n}\n"
+        );
+        assert_eq!(
+            format_with_options("{\n  \"a\": 1, // one\n  \"b\": 2\n}", &options),
+            "{\n  \"a\": 1, // one\n  \"b\": 2\n}\n"
+        );
+        assert_eq!(
+            format_with_options(r#"[{"a": {"b": 1, "c": 2, "d": 3}}]"#, &options),
+            "[\n  {\n    \"a\": {\n      \"b\": 1,\n      \"c\": 2,\n      \"d\": 3\n    }\n  }\n]\n"
+        );
+        assert_eq!(
+            format_with_options("{\"a\": [\n  1\n]}", &options),
+            "{\n  \"a\": [\n    1\n  ]\n}\n"
+        );
+
+        let options = FormatOptions {
+            object_rows_threshold: Some(3),
+            max_width: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("{\n  \"a\": 1,\n  \"b\": 2\n}", &options),
+            "{\n  \"a\": 1,\n  \"b\": 2\n}\n"
+        );
+    }
+
+    #[test]
+    fn hash_comments() {
+        let options = FormatOptions {
+            allow_hash_comments: true,
+            ..Default::default()
+        };
+        let input = r##"# leading
+{"a":1, # one
+  "b#": "#2",   // two
+  /* # */ "c": [3,4], # [5]
+}"##;
+        let expected = r##"# leading
+{
+  "a": 1, # one
+  "b#": "#2", // two
+  /* # */
+  "c": [3, 4], # [5]
+}
+"##;
+        assert_eq!(format_text(input, &options).expect("bug"), expected);
+        assert_eq!(format_text(expected, &options).expect("bug"), expected);
+        assert!(format_text(input, &FormatOptions::default()).is_err());
+
+        let options = FormatOptions {
+            allow_hash_comments: true,
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text(input, &options).expect("bug"),
+            "{\n  \"a\": 1,\n  \"b#\": \"#2\",\n  \"c\": [3, 4]\n}\n"
+        );
+    }
+
+    #[test]
+    fn max_width_key_length() {
+        // The single-line form is 50 columns wide, of which only 2 are taken by the values.
+        let input = r#"{"a_very_long_key_name": 1, "another_long_key": 2}"#;
+        assert_eq!(input.len(), 50);
+
+        let options = FormatOptions {
+            max_width: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(format_with_options(input, &options), format!("{input}\n"));
+
+        let options = FormatOptions {
+            max_width: Some(49),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(input, &options),
+            "{\n  \"a_very_long_key_name\": 1,\n  \"another_long_key\": 2\n}\n"
+        );
+    }
+
+    #[test]
+    fn max_width_nested_levels() {
+        // `[1, 2, 3, 4]` and `[1, 2, 3, 4, 5]` fit within 16 columns on their own, but not after
+        // the indentation and keys in front of them, while `[5]` still fits there.
+        let options = FormatOptions {
+            max_width: Some(16),
+            ..Default::default()
+        };
+        let input = r#"{"a": {"bb": {"c": [1, 2, 3, 4]}, "d": [5]}, "e": [[1, 2, 3, 4, 5]]}"#;
+        let expected = r#"{
+  "a": {
+    "bb": {
+      "c": [
+        1,
+        2,
+        3,
+        4
+      ]
+    },
+    "d": [5]
+  },
+  "e": [
+    [
+      1,
+      2,
+      3,
+      4,
+      5
+    ]
+  ]
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+
+        // The same goes for deeper indentation steps.
+        let options = FormatOptions {
+            max_width: Some(16),
+            indent_spec: Some(vec![4]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[[1, 2, 3], [1, 2, 3, 4, 5]]", &options),
+            "[\n    [1, 2, 3],\n    [\n        1,\n        2,\n        3,\n        4,\n        5\n    ]\n]\n"
+        );
+    }
+
+    #[test]
+    fn comment_only() {
+        let options = FormatOptions {
+            allow_comment_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text("  // TODO   \n", &options).expect("bug"),
+            "// TODO\n"
+        );
+        assert_eq!(format_text("// TODO", &options).expect("bug"), "// TODO\n");
+        assert_eq!(
+            format_text("/*\n  * license\n  */\n\n\n// TODO\n", &options).expect("bug"),
+            "/*\n  * license\n  */\n\n// TODO\n"
+        );
+        assert_eq!(
+            format_text("/* a */ /* b */\r\n", &options).expect("bug"),
+            "/* a */\r\n/* b */\r\n"
+        );
+        assert_eq!(format_text("", &options).expect("bug"), "\n");
+        assert!(format_text("// TODO\n{", &options).is_err());
+        assert!(format_text("// TODO\n", &FormatOptions::default()).is_err());
+
+        let options = FormatOptions {
+            allow_comment_only: true,
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(format_text("// TODO\n", &options).expect("bug"), "\n");
+    }
+
+    #[test]
+    fn strip_produces_strict_json() {
+        let input = r#"// header
+{
+  "a": [1, 2, /* two */], // a
+  "b": {"c": 3 /* c */,},
+  "d": [
+    4, // four
+  ],
+  "e": {
+    "f": 5,
+    // end of e
+  },
+}"#;
+        let expected = r#"{
+  "a": [1, 2],
+  "b": {"c": 3},
+  "d": [
+    4
+  ],
+  "e": {
+    "f": 5
+  }
+}
+"#;
+        for strip_comments_to_blank_lines in [false, true] {
+            for sort_keys in [None, Some(KeyOrder::Lexical)] {
+                let options = FormatOptions {
+                    strip: true,
+                    strip_comments_to_blank_lines,
+                    sort_keys,
+                    ..Default::default()
+                };
+                let output = format_with_options(input, &options);
+                nojson::RawJson::parse(&output)
+                    .unwrap_or_else(|e| panic!("{e}\nOUTPUT:\n{output}"));
+                if !strip_comments_to_blank_lines {
+                    assert_eq!(output, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn preserved_comment_prefixes() {
+        let input = "//# sourceMappingURL=a.map\n{\"a\": 1, // c\n//@   directive  \n\"b\": [2, //# x\n3],\n  /*#  keep\n      me */\n\"c\": 4}";
+        let prefixes = vec!["//#".to_owned(), "//@".to_owned(), "/*#".to_owned()];
+        let options = FormatOptions {
+            strip: true,
+            preserved_comment_prefixes: prefixes.clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(input, &options),
+            "//# sourceMappingURL=a.map\n{\n  \"a\": 1,\n  //@   directive\n  \"b\": [\n    2, //# x\n    3\n  ],\n  /*#  keep\n      me */\n  \"c\": 4\n}\n"
+        );
+
+        // They are neither rewritten nor turned into fields.
+        let options = FormatOptions {
+            trailing_comment_style: Some(TrailingCommentStyle::Auto),
+            comments_to_fields: true,
+            preserved_comment_prefixes: prefixes,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("{//@ d\n\"a\": [1 //# x\n, 2 // y\n, 3]}", &options),
+            "{ //@ d\n  \"a\": [\n    1 //# x\n    ,\n    2 /* y */,\n    3\n  ]\n}\n"
+        );
+    }
+
+    #[test]
+    fn strip_trims_document_edges() {
+        let options = FormatOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("\n\n \n  {\"a\": 1}\n\n\t\n\n", &options),
+            "{\"a\": 1}\n"
+        );
+        assert_eq!(
+            format_with_options("\n// header\n\n[1,\n2]\n\n/* footer */\n\n", &options),
+            "[\n  1,\n  2\n]\n"
+        );
+        assert_eq!(
+            format_text("\r\n\r\n{\"a\": 1}\r\n\r\n", &options).expect("bug"),
+            "{\"a\": 1}\r\n"
+        );
+    }
+
+    #[test]
+    fn member_value_trailing_block_comment() {
+        assert_eq!(
+            format(r#"{"a": null /* todo */}"#),
+            "{\n  \"a\": null /* todo */\n}\n"
+        );
+        assert_eq!(
+            format(r#"{"a": null /* todo */, "b": {"c": 1 /* one */}}"#),
+            "{\n  \"a\": null /* todo */,\n  \"b\": {\n    \"c\": 1 /* one */\n  }\n}\n"
+        );
+        assert_eq!(
+            format("[\n  1 /* one */,\n  2 // two\n  , 3\n]"),
+            "[\n  1 /* one */,\n  2 // two\n  ,\n  3\n]\n"
+        );
+        assert!(nojson::RawJson::parse_jsonc(r#"{"a": /* todo */ }"#).is_err());
+    }
+
+    #[test]
+    fn comma_before_leading_comment() {
+        let options = FormatOptions {
+            collapse_width: Some(80),
+            ..Default::default()
+        };
+        for text in [
+            "[1, /* c */ 2]",
+            "[1,\n// c\n2]",
+            r#"{"a": 1, /* c */ "b": [1, /* d */ 2]}"#,
+        ] {
+            for output in [format(text), format_with_options(text, &options)] {
+                assert!(
+                    output.lines().all(|line| line == line.trim_end()),
+                    "INPUT:\n{text}\nOUTPUT:\n{output}"
+                );
+            }
+        }
+        assert_eq!(format("[1,\n// c\n2]"), "[\n  1,\n  // c\n  2\n]\n");
+        assert_eq!(format("[1, /* c */ 2]"), "[\n  1, /* c */\n  2\n]\n");
+    }
+
+    #[test]
+    fn normalize_numbers_js() {
+        let cases = [
+            ("0.1", "0.1"),
+            ("0.10", "0.1"),
+            ("1e21", "1e+21"),
+            ("100000000000000000000", "100000000000000000000"),
+            ("1000000000000000000000", "1e+21"),
+            ("12345678901234567890", "12345678901234567000"),
+            ("1.5E3", "1500"),
+            ("-0", "0"),
+            ("-0.0", "0"),
+            ("0.000001", "0.000001"),
+            ("0.0000001", "1e-7"),
+            ("1.2345e-7", "1.2345e-7"),
+            ("123.456", "123.456"),
+            ("-1.0", "-1"),
+            ("5e-324", "5e-324"),
+            ("1.7976931348623157e308", "1.7976931348623157e+308"),
+            ("0.30000000000000004", "0.30000000000000004"),
+            // Exactly halfway between two shortest candidates.
+            ("73655322632603.125", "73655322632603.12"),
+            ("1e400", "1e400"),
+        ];
+        let options = FormatOptions {
+            normalize_numbers: Some(NumberFormat::Js),
+            ..Default::default()
+        };
+        for (input, expected) in cases {
+            assert_eq!(
+                format_with_options(input, &options),
+                format!("{expected}\n"),
+                "{input}"
+            );
+        }
+
+        let options = FormatOptions {
+            normalize_numbers: Some(NumberFormat::Js),
+            numbers_as_strings: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(r#"{"1.0": 1.0}"#, &options),
+            "{\"1.0\": \"1\"}\n"
+        );
+    }
+
+    #[test]
+    fn format_failure_context() {
+        // A writer that fails once the output reaches a given length.
+        struct FailingWriter {
+            output: String,
+            limit: usize,
+        }
+
+        impl std::fmt::Write for FailingWriter {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                if self.output.len() + s.len() > self.limit {
+                    return Err(std::fmt::Error);
+                }
+                self.output.push_str(s);
+                Ok(())
+            }
+        }
+
+        let text = "{\n  \"a\": 1,\n  \"b\": [2, 3]\n}";
+        let (json, comment_ranges) = nojson::RawJson::parse_jsonc(text).expect("bug");
+        let writer = FailingWriter {
+            output: String::new(),
+            limit: 20,
+        };
+        let options = FormatOptions::default();
+        let mut formatter = Formatter::with_options(text, comment_ranges, writer, &options);
+        let error = formatter.format(json.value()).expect_err("bug");
+        let error = format_failure(text, formatter.text_position, error);
+        assert_eq!(
+            error.to_string(),
+            r#"failed to format JSON at byte position 20: an error occurred when formatting an argument
+
+INPUT:
+     |  "a": 1,
+   3 |  "b": [2, 3]
+     |        ^ error"#
+        );
+    }
+
+    #[test]
+    fn trailing_content() {
+        let input = "{\"a\":1} // done\nINFO: generated by foo\n  notes  \n";
+
+        let options = FormatOptions {
+            trailing_content: Some(TrailingContent::Preserve),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text(input, &options).expect("bug"),
+            "{\"a\": 1} // done\nINFO: generated by foo\n  notes  \n"
+        );
+
+        let options = FormatOptions {
+            trailing_content: Some(TrailingContent::Drop),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text(input, &options).expect("bug"),
+            "{\"a\": 1} // done\n"
+        );
+        assert_eq!(format_text("[1,2]x", &options).expect("bug"), "[1, 2]\n");
+        assert!(format_text("[1,", &options).is_err());
+
+        assert!(format_text(input, &FormatOptions::default()).is_err());
+    }
+
+    #[test]
+    fn collapse_width() {
+        let options = FormatOptions {
+            collapse_width: Some(12),
+            max_width: Some(20),
+            ..Default::default()
+        };
+        // 9 columns: collapsed.
+        assert_eq!(
+            format_with_options("[\n  1,\n  2,\n  3\n]", &options),
+            "[1, 2, 3]\n"
+        );
+        // 15 columns, between the two widths: it neither collapses nor wraps.
+        let multiline = "[\n  10,\n  20,\n  30,\n  40\n]\n";
+        assert_eq!(format_with_options(multiline, &options), multiline);
+        let single_line = "[10, 20, 30, 40]\n";
+        assert_eq!(format_with_options(single_line, &options), single_line);
+        // 24 columns: wrapped.
+        assert_eq!(
+            format_with_options("[10, 20, 30, 40, 50, 60]", &options),
+            "[\n  10,\n  20,\n  30,\n  40,\n  50,\n  60\n]\n"
+        );
+
+        let options = FormatOptions {
+            collapse_width: Some(16),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("{\n  \"a\": {\n    \"b\": 1\n  }\n}", &options),
+            "{\"a\": {\"b\": 1}}\n"
+        );
+        assert_eq!(
+            format_with_options("{\n  \"a\": [\n    1\n  ], // a\n  \"b\": 2\n}", &options),
+            "{\n  \"a\": [1], // a\n  \"b\": 2\n}\n"
+        );
+
+        // Only the array on the first line fits.
+        let options = FormatOptions {
+            collapse_width: Some(8),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[\n  [\n    1\n  ],\n  [\n    22\n  ]\n]", &options),
+            "[\n  [1],\n  [22]\n]\n"
+        );
+    }
+
+    #[test]
+    fn comments_to_fields() {
+        let options = FormatOptions {
+            comments_to_fields: true,
+            ..Default::default()
+        };
+        let input = r#"{
+  // Server port.
+  "port": 8080, // not converted
+
+  /* kept */
+  // Host name
+  // (or "localhost").
+  "host": "example.com",
+  "nested": {
+    // inner
+    "a": 1
+  }
+}"#;
+        let expected = r#"{
+  "port__comment": "Server port.",
+  "port": 8080, // not converted
+
+  /* kept */
+  "host__comment": "Host name\n(or \"localhost\").",
+  "host": "example.com",
+  "nested": {
+    "a__comment": "inner",
+    "a": 1
+  }
+}
+"#;
+        assert_eq!(format_with_options(input, &options), expected);
+        assert_eq!(format_with_options(expected, &options), expected);
+
+        let options = FormatOptions {
+            comments_to_fields: true,
+            sort_keys: Some(KeyOrder::Lexical),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("{\n  // b\n  \"b\": 1,\n  \"a\": 2\n}", &options),
+            "{\n  \"a\": 2,\n  \"b__comment\": \"b\",\n  \"b\": 1\n}\n"
+        );
+    }
+
+    #[test]
+    fn canonical_hash_stability() {
+        assert_eq!(
+            sha256(b"abc")
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(sha256(&[b'a'; 1000])[..4], [0x41, 0xed, 0xec, 0xe4]);
+
+        let options = FormatOptions::default();
+        let a = canonical_hash(r#"{"b": [1.0, "A"], "a": null}"#, &options).expect("bug");
+        let b = canonical_hash(
+            "// config\n{\n  \"a\": null, /* none */\n  \"b\": [\n    1,\n    \"A\",\n  ],\n}\n",
+            &options,
+        )
+        .expect("bug");
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 64);
+
+        let c = canonical_hash(r#"{"b": [1, "A"], "a": false}"#, &options).expect("bug");
+        assert_ne!(a, c);
+
+        // The options that relax parsing apply as when formatting.
+        assert!(canonical_hash("{\"a\": 1} junk", &options).is_err());
+        let options = FormatOptions {
+            trailing_content: Some(TrailingContent::Preserve),
+            ..Default::default()
+        };
+        assert_eq!(
+            canonical_hash("{\"a\": 1} junk", &options).expect("bug"),
+            canonical_hash("{\"a\": 1}", &options).expect("bug")
+        );
+
+        assert!(canonical_hash("// only a comment\n", &options).is_err());
+        let options = FormatOptions {
+            allow_comment_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            canonical_hash("// only a comment\n", &options).expect("bug"),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn hug_closing_brackets() {
+        let options = FormatOptions {
+            hug_closing_brackets: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[[[1,\n2]]]", &options),
+            "[\n  [\n    [\n      1,\n      2\n    ]]]\n"
+        );
+        assert_eq!(
+            format_with_options("{\"a\": [[1], {\"b\": [\n2]}]}", &options),
+            "{\n  \"a\": [\n    [1],\n    {\n      \"b\": [\n        2\n      ]}]}\n"
+        );
+
+        // Single-line containers, trailing commas and comments keep the brackets apart.
+        assert_eq!(format_with_options("[[1]\n]", &options), "[\n  [1]\n]\n");
+        assert_eq!(
+            format_with_options("[[1,\n2],]", &options),
+            "[\n  [\n    1,\n    2\n  ],\n]\n"
+        );
+        assert_eq!(
+            format_with_options("[[1,\n2] // c\n]", &options),
+            "[\n  [\n    1,\n    2\n  ] // c\n]\n"
+        );
+
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            ..options
+        };
+        assert_eq!(
+            format_with_options("{\"b\": {\"c\": [1,\n2]}, \"a\": 1}", &options),
+            "{\n  \"a\": 1,\n  \"b\": {\n    \"c\": [\n      1,\n      2\n    ]}}\n"
+        );
+    }
+
+    #[test]
+    fn closing_bracket_indent() {
+        let options = FormatOptions {
+            closing_bracket_indent: ClosingBracketIndent::MatchContent,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("{\"a\": [1,\n2], \"b\": [3]}", &options),
+            "{\n  \"a\": [\n    1,\n    2\n    ],\n  \"b\": [3]\n  }\n"
+        );
+        assert_eq!(
+            format_with_options("[1, // one\n2 // two\n]", &options),
+            "[\n  1, // one\n  2 // two\n  ]\n"
+        );
+        assert_eq!(
+            format_with_options("[[],\n{}]", &options),
+            "[\n  [],\n  {}\n  ]\n"
+        );
+    }
+
+    #[test]
+    fn trailing_comment_style_auto() {
+        let options = FormatOptions {
+            trailing_comment_style: Some(TrailingCommentStyle::Auto),
+            max_width: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[1 // one\n, 2 // two\n]", &options),
+            "[\n  1 /* one */,\n  2 // two\n]\n"
+        );
+        assert_eq!(
+            format_with_options("[1, // a rather long comment\n2, // short\n]", &options),
+            "[\n  1,\n  // a rather long comment\n  2, // short\n]\n"
+        );
+
+        // Alignment padding is dropped before moving a comment.
+        let options = FormatOptions {
+            comment_align_to_value: true,
+            max_width: Some(22),
+            ..options
+        };
+        assert_eq!(
+            format_with_options("{\"a\": 1, // comment\n\"bbbbbbbb\": 2, // b\n}", &options),
+            "{\n  \"a\": 1, // comment\n  \"bbbbbbbb\": 2, // b\n}\n"
+        );
+        assert_eq!(
+            format_with_options("{\"a\": 1, // c\n\"bbbbbbbb\": 2, // b\n}", &options),
+            "{\n  \"a\": 1,        // c\n  \"bbbbbbbb\": 2, // b\n}\n"
+        );
+
+        // Without the option, the comma goes on its own line.
+        assert_eq!(format("[1 // one\n, 2]"), "[\n  1 // one\n  ,\n  2\n]\n");
+    }
+
+    #[test]
+    fn layout_directives() {
+        assert_eq!(
+            format(r#"{"a": /* jcfmt:expand */ [1, 2], "b": [3]}"#),
+            "{\n  \"a\": /* jcfmt:expand */\n  [\n    1,\n    2\n  ],\n  \"b\": [3]\n}\n"
+        );
+        assert_eq!(
+            format("/* jcfmt:collapse */ {\"a\": [1,\n2],\n\"b\": {\"c\": 3\n}}"),
+            "/* jcfmt:collapse */\n{\"a\": [1, 2], \"b\": {\"c\": 3}}\n"
+        );
+
+        // Directives override the width options.
+        let options = FormatOptions {
+            max_width: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options("[\n/* jcfmt:collapse */\n[1, 2, 3, 4, 5]]", &options),
+            "[\n  /* jcfmt:collapse */\n  [1, 2, 3, 4, 5]\n]\n"
+        );
+
+        // Containers with comments and empty containers keep their usual layout.
+        assert_eq!(
+            format("/* jcfmt:collapse */ [1, // one\n2]"),
+            "/* jcfmt:collapse */\n[\n  1, // one\n  2\n]\n"
+        );
+        assert_eq!(
+            format("[/* jcfmt:expand */ {}]"),
+            "[ /* jcfmt:expand */\n  {}\n]\n"
+        );
+
+        // A directive before a closing bracket applies to nothing, so stripping it leaves no
+        // trace.
+        let options = FormatOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(
+                "{\"a\": [/* jcfmt:expand */], \"b\": {/* jcfmt:expand */}}",
+                &options
+            ),
+            "{\"a\": [], \"b\": {}}\n"
+        );
+    }
+
+    #[test]
+    fn key_quotes_preserved() {
+        let single_line = r#"{"a\u0062c": 1, "\/x": {"\u00e9\t": 2}}"#;
+        let multiline = "{\"a\\u0062c\": 1,\n\"\\/x\": {\"\\u00e9\\t\": 2}}";
+        assert_eq!(format(single_line), format!("{single_line}\n"));
+        assert_eq!(
+            format(multiline),
+            "{\n  \"a\\u0062c\": 1,\n  \"\\/x\": {\"\\u00e9\\t\": 2}\n}\n"
+        );
+
+        // Sorting compares the unescaped keys but writes them as they are.
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(single_line, &options),
+            "{\"\\/x\": {\"\\u00e9\\t\": 2}, \"a\\u0062c\": 1}\n"
+        );
+        assert_eq!(
+            format_with_options(multiline, &options),
+            "{\n  \"\\/x\": {\"\\u00e9\\t\": 2},\n  \"a\\u0062c\": 1\n}\n"
+        );
+
+        let options = FormatOptions {
+            normalize_escapes: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(single_line, &options),
+            "{\"abc\": 1, \"/x\": {\"\u{e9}\\t\": 2}}\n"
+        );
+    }
+
+    #[test]
+    fn formatter_with_options() {
+        let text = "{\"b\": [1,\n2], // two\n\"a\": {}}";
+        let (json, comment_ranges) = nojson::RawJson::parse_jsonc(text).expect("bug");
+        let options = FormatOptions {
+            sort_keys: Some(KeyOrder::Lexical),
+            indent_spec: Some(vec![4]),
+            ..Default::default()
+        };
+        let mut output = String::new();
+        Formatter::with_options(text, comment_ranges, &mut output, &options)
+            .format(json.value())
+            .expect("bug");
+        assert_eq!(
+            output,
+            "{\n    \"a\": {},\n    \"b\": [\n        1,\n        2\n    ] // two\n}\n"
+        );
+    }
+
+    #[test]
+    fn append_stats() {
+        let options = FormatOptions {
+            append_stats: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(r#"{"a": [1, {"b": null}], "c": "x"} // end"#, &options),
+            "{\"a\": [1, {\"b\": null}], \"c\": \"x\"} // end\n/* 2 objects, 1 array, 3 values */\n"
+        );
+        assert_eq!(
+            format_with_options("[]", &options),
+            "[]\n/* 0 objects, 1 array, 0 values */\n"
+        );
+        assert_eq!(
+            format_with_options("1", &options),
+            "1\n/* 0 objects, 0 arrays, 1 value */\n"
+        );
+    }
+
+    #[test]
+    fn multiline_value_expands_parents() {
+        assert_eq!(
+            format(r#"{"a": [1, /* one */ 2], "b": 3}"#),
+            "{\n  \"a\": [\n    1, /* one */\n    2\n  ],\n  \"b\": 3\n}\n"
+        );
+
+        // Stripped directives still expand the containers around them.
+        let options = FormatOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_options(r#"{"a": /* jcfmt:expand */ [1, 2], "b": 3}"#, &options),
+            "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": 3\n}\n"
+        );
+        assert_eq!(
+            format_with_options(
+                "/* jcfmt:collapse */ [{\"a\": /* jcfmt:expand */ [1]}]",
+                &options
+            ),
+            "[\n  {\n    \"a\": [\n      1\n    ]\n  }\n]\n"
+        );
+    }
+
+    #[test]
+    fn format_stream_records() {
+        let text = "{\"a\":1}\n\n[1,2] // two\n{\"b\":\n[3]\r\n";
+        let results = format_stream(text, &FormatOptions::default()).collect::<Vec<_>>();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().expect("bug"), "{\"a\": 1}\n");
+        assert_eq!(results[1].as_ref().expect("bug"), "[1, 2] // two\n");
+        assert!(matches!(results[2], Err(FormatError::Parse(_))));
+        assert_eq!(results[3].as_ref().expect("bug"), "[3]\n");
+    }
+}
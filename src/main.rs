@@ -1,9 +1,12 @@
-use std::collections::BTreeMap;
+use std::io::{Read, Write as _};
 use std::num::NonZeroUsize;
-use std::ops::Range;
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
-const INDENT_SIZE: usize = 2;
+use jcfmt::{
+    ClosingBracketIndent, EmptyValues, FormatError, FormatOptions, KeyOrder, NumberFormat,
+    TrailingCommentStyle, TrailingContent, Warning,
+};
 
 fn main() -> noargs::Result<()> {
     let mut args = noargs::raw_args();
@@ -11,6 +14,25 @@ fn main() -> noargs::Result<()> {
     args.metadata_mut().app_name = env!("CARGO_PKG_NAME");
     args.metadata_mut().app_description = env!("CARGO_PKG_DESCRIPTION");
 
+    // Hidden maintenance mode that isn't listed in the help.
+    if std::env::args().skip(1).eq(["--selfcheck"]) {
+        let mut failed = false;
+        for result in jcfmt::selfcheck() {
+            let (fixture, options) = (result.fixture, result.options);
+            match result.error {
+                None => println!("ok   {fixture} ({options})"),
+                Some(error) => {
+                    println!("FAIL {fixture} ({options}): {error}");
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     if noargs::VERSION_FLAG.take(&mut args).is_present() {
         println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
         return Ok(());
@@ -19,7 +41,228 @@ fn main() -> noargs::Result<()> {
 
     let strip = noargs::flag("strip")
         .short('s')
-        .doc("Remove all comments and trailing commas from the JSON output")
+        .doc("Remove all comments, a leading #! line and trailing commas from the JSON output")
+        .take(&mut args)
+        .is_present();
+    let strip_comments = noargs::opt("strip-comments")
+        .ty("remove|blank")
+        .doc(concat!(
+            "Same as '--strip', but with 'blank' each removed comment line\n",
+            "is replaced with an empty line so that line numbers don't shift"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| match a.value() {
+            "remove" => Ok(false),
+            "blank" => Ok(true),
+            _ => Err("expected 'remove' or 'blank'"),
+        })?;
+    let mut preserved_comment_prefixes = Vec::new();
+    while let Some(prefix) = noargs::opt("comment-marker-preserve")
+        .ty("PREFIX")
+        .doc(concat!(
+            "Keep comments starting with this prefix (e.g., '//#') as they are, even when\n",
+            "stripping or rewriting comments (can be given multiple times)"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse::<String>())?
+    {
+        preserved_comment_prefixes.push(prefix);
+    }
+    let comment_align_to_value = noargs::flag("comment-align-to-value")
+        .doc("Align trailing comments of multiline object members to a common column")
+        .take(&mut args)
+        .is_present();
+    let trailing_newline_if_missing_only = noargs::flag("trailing-newline-if-missing-only")
+        .doc("Keep the input's trailing newlines as-is, adding one only if none are present")
+        .take(&mut args)
+        .is_present();
+    let parse_only_report = noargs::flag("parse-only-report")
+        .doc("Only parse the input and print the diagnostics as a JSON array")
+        .take(&mut args)
+        .is_present();
+    let indent_spec = noargs::opt("indent-spec")
+        .ty("COLUMNS")
+        .doc(concat!(
+            "Comma-separated indentation columns for nesting levels 1, 2, ... (e.g., '4,6,10');\n",
+            "deeper levels repeat the last step"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| parse_indent_spec(a.value()))?;
+    let max_width = noargs::opt("max-width")
+        .ty("COLUMNS")
+        .doc(concat!(
+            "Expand arrays and objects whose single-line form would exceed this width\n",
+            "(a line of exactly this width still fits)"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let max_width_ignore_comments = noargs::flag("max-width-ignore-comments")
+        .doc("Exclude trailing comments when measuring lines for '--max-width'")
+        .take(&mut args)
+        .is_present();
+    let collapse_width = noargs::opt("collapse-width")
+        .ty("COLUMNS")
+        .doc("Collapse multiline arrays and objects onto a single line if it fits this width")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let array_rows_threshold = noargs::opt("array-rows-threshold")
+        .ty("N")
+        .doc(concat!(
+            "Put each element of arrays with at least N elements on its own line,\n",
+            "and collapse smaller arrays onto a single line when they fit"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let object_rows_threshold = noargs::opt("object-rows-threshold")
+        .ty("N")
+        .doc(concat!(
+            "Put each member of objects with at least N members on its own line,\n",
+            "and collapse smaller objects onto a single line when they fit"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let hug_closing_brackets = noargs::flag("hug-closing-brackets")
+        .doc("Write the closing brackets of nested multiline arrays and objects on one line")
+        .take(&mut args)
+        .is_present();
+    let closing_bracket_indent = noargs::opt("closing-bracket-indent")
+        .ty("match-open|match-content")
+        .default("match-open")
+        .doc("Indent closing brackets like the opening bracket's line or like the content")
+        .take(&mut args)
+        .then(|a| match a.value() {
+            "match-open" => Ok(ClosingBracketIndent::MatchOpen),
+            "match-content" => Ok(ClosingBracketIndent::MatchContent),
+            _ => Err("expected 'match-open' or 'match-content'"),
+        })?;
+    let trailing_comment_style = noargs::opt("trailing-comment-style")
+        .ty("auto")
+        .doc(concat!(
+            "With 'auto', convert a trailing '//' comment followed by a comma to '/* */',\n",
+            "and move one that would exceed '--max-width' to the next line"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| match a.value() {
+            "auto" => Ok(TrailingCommentStyle::Auto),
+            _ => Err("expected 'auto'"),
+        })?;
+    let sort_keys = noargs::flag("sort-keys")
+        .doc("Sort object members by key (in Unicode code point order, regardless of the locale)")
+        .take(&mut args)
+        .is_present();
+    let key_sort_numeric_first = noargs::flag("key-sort-numeric-first")
+        .doc(concat!(
+            "Sort object members with numeric keys first (in numeric order),\n",
+            "followed by the other keys (implies '--sort-keys')"
+        ))
+        .take(&mut args)
+        .is_present();
+    let mut sort_keys_at = Vec::new();
+    while let Some(pointer) = noargs::opt("sort-keys-at")
+        .ty("JSON_POINTER")
+        .doc(concat!(
+            "Only sort the objects at this JSON Pointer (e.g., '/foo/bar'), which can be\n",
+            "given multiple times (implies '--sort-keys')"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| {
+            let pointer = a.value();
+            if pointer.is_empty() || pointer.starts_with('/') {
+                Ok(pointer.to_owned())
+            } else {
+                Err("expected an empty string or a string starting with '/'")
+            }
+        })?
+    {
+        sort_keys_at.push(pointer);
+    }
+    let sort_keys_keep_comments_fixed = noargs::flag("sort-keys-keep-comments-fixed")
+        .doc("Keep standalone comments in their original order instead of moving them with sorted members")
+        .take(&mut args)
+        .is_present();
+    let allow_hash_comments = noargs::flag("allow-hash-comments")
+        .doc("Also accept '#' line comments, which are kept as-is")
+        .take(&mut args)
+        .is_present();
+    let allow_comment_only = noargs::flag("allow-comment-only")
+        .doc("Accept input that contains only comments and no JSON value")
+        .take(&mut args)
+        .is_present();
+    let trailing_content = noargs::opt("allow-trailing-content")
+        .ty("preserve|drop")
+        .doc("Accept non-comment content after the JSON value, and keep or remove it")
+        .take(&mut args)
+        .present_and_then(|a| match a.value() {
+            "preserve" => Ok(TrailingContent::Preserve),
+            "drop" => Ok(TrailingContent::Drop),
+            _ => Err("expected 'preserve' or 'drop'"),
+        })?;
+    let comments_to_fields = noargs::flag("comments-to-fields")
+        .doc("Replace '//' comments before object members with '\"<key>__comment\"' members")
+        .take(&mut args)
+        .is_present();
+    let normalize_escapes = noargs::flag("normalize-escapes")
+        .doc("Re-encode strings using the minimal escape sequences")
+        .take(&mut args)
+        .is_present();
+    let normalize_numbers = noargs::opt("normalize-numbers")
+        .ty("js")
+        .doc("Rewrite numbers as JavaScript's 'JSON.stringify()' would")
+        .take(&mut args)
+        .present_and_then(|a| match a.value() {
+            "js" => Ok(NumberFormat::Js),
+            _ => Err("expected 'js'"),
+        })?;
+    let numbers_as_strings = noargs::flag("numbers-as-strings")
+        .doc("Write every number as a string (e.g., '123' becomes '\"123\"')")
+        .take(&mut args)
+        .is_present();
+    let empty_to_null = noargs::opt("empty-to-null")
+        .ty("KINDS")
+        .doc(concat!(
+            "Write empty values of these comma-separated kinds as 'null'\n",
+            "('string', 'array' and 'object')"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| parse_empty_values(a.value()))?
+        .unwrap_or_default();
+    let preview = noargs::flag("preview")
+        .doc("Enable preview-only options whose output is not meant to be round-tripped")
+        .take(&mut args)
+        .is_present();
+    let preview_array_limit: Option<usize> = noargs::opt("preview-array-limit")
+        .ty("N")
+        .doc("Only show the first N elements of each array (requires '--preview')")
+        .take(&mut args)
+        .present_and_then(|a| {
+            if !preview {
+                return Err("requires '--preview'".to_owned());
+            }
+            a.value().parse().map_err(|e| format!("{e}"))
+        })?;
+    let append_stats = noargs::flag("append-stats")
+        .doc("Append a comment with the numbers of objects, arrays and values in the input")
+        .take(&mut args)
+        .is_present();
+    let changed_lines = noargs::opt("changed-only")
+        .ty("LINES")
+        .doc(concat!(
+            "Only reformat the members and elements that start or end on the given lines\n",
+            "(e.g., '3-5,10-12')"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| parse_line_ranges(a.value()))?;
+    let quiet = noargs::flag("quiet")
+        .short('q')
+        .doc("Don't print warnings (errors are still printed)")
+        .take(&mut args)
+        .is_present();
+    let emit_hash = noargs::flag("emit-hash")
+        .doc("Print the SHA-256 hash of the canonical form of the input to stderr")
+        .take(&mut args)
+        .is_present();
+    let number_lines = noargs::flag("number-lines")
+        .doc("Prefix each output line with its line number (for viewing only)")
         .take(&mut args)
         .is_present();
     let output_file: Option<PathBuf> = noargs::opt("output-file")
@@ -27,671 +270,304 @@ fn main() -> noargs::Result<()> {
         .ty("PATH")
         .doc("Write output to a file instead of stdout")
         .take(&mut args)
-        .present_and_then(|a| a.value().parse())?;
+        .present_and_then(|a| {
+            if number_lines {
+                return Err("can't be combined with '--number-lines'".to_owned());
+            }
+            a.value().parse().map_err(|e| format!("{e}"))
+        })?;
+    let output_encoding = noargs::opt("output-encoding")
+        .ty("utf8|utf16le|latin1")
+        .default("utf8")
+        .doc("Encoding of the output (characters that it can't represent are an error)")
+        .take(&mut args)
+        .then(|a| match a.value() {
+            "utf8" => Ok(OutputEncoding::Utf8),
+            "utf16le" => Ok(OutputEncoding::Utf16Le),
+            "latin1" => Ok(OutputEncoding::Latin1),
+            _ => Err("expected 'utf8', 'utf16le' or 'latin1'"),
+        })?;
+    let backup = noargs::flag("backup")
+        .doc("Copy the existing '--output-file' to '<PATH>.bak' before overwriting it")
+        .take(&mut args)
+        .is_present();
+    if backup && output_file.is_none() {
+        return Err(noargs::Error::other(
+            &args,
+            "'--backup' requires '--output-file'",
+        ));
+    }
 
     if let Some(help) = args.finish()? {
         print!("{help}");
         return Ok(());
     }
 
-    let text = std::io::read_to_string(std::io::stdin())?;
-    let (json, comment_ranges) =
-        nojson::RawJson::parse_jsonc(&text).map_err(|e| format_json_parse_error(&text, e))?;
-
-    let mut output = String::new();
-    let mut formatter = Formatter::new(&text, comment_ranges, &mut output, strip);
-    formatter.format(json.value())?;
-
-    if let Some(path) = output_file {
-        std::fs::write(path, output)?;
-    } else {
-        print!("{output}");
-    }
-
-    Ok(())
-}
-
-#[derive(Debug)]
-struct Formatter<'a, W> {
-    text: &'a str,
-    comment_ranges: BTreeMap<usize, usize>,
-    writer: W,
-    level: usize,
-    text_position: usize,
-    multiline_mode: bool,
-    strip: bool,
-}
-
-impl<'a, W: std::fmt::Write> Formatter<'a, W> {
-    fn new(text: &'a str, mut comment_ranges: Vec<Range<usize>>, writer: W, strip: bool) -> Self {
-        if strip {
-            comment_ranges.clear();
-        }
-        Self {
-            text,
-            comment_ranges: comment_ranges
-                .into_iter()
-                .map(|r| (r.start, r.end))
-                .collect(),
-            writer,
-            level: 0,
-            text_position: 0,
-            multiline_mode: false,
-            strip,
-        }
-    }
-
-    fn format(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
-        self.multiline_mode = self.is_newline_needed(value);
-        self.format_value(value)?;
-        self.format_comments(self.text.len())?;
-        writeln!(self.writer)?;
-        Ok(())
-    }
-
-    fn format_value(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
-        if self.multiline_mode {
-            self.format_comments(value.position())?;
-            self.indent(value.position())?;
-        }
-        self.format_value_content(value)?;
-        Ok(())
-    }
-
-    fn format_member_value(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
-        if self.contains_comment(value.position()) {
-            self.format_comments(value.position())?;
-            self.indent(value.position())?;
+    let options = FormatOptions {
+        strip: strip || strip_comments.is_some(),
+        strip_comments_to_blank_lines: strip_comments == Some(true),
+        preserved_comment_prefixes,
+        comment_align_to_value,
+        trailing_newline_if_missing_only,
+        indent_spec,
+        max_width,
+        max_width_ignore_comments,
+        collapse_width,
+        trailing_comment_style,
+        sort_keys: if key_sort_numeric_first {
+            Some(KeyOrder::NumericFirst)
+        } else if sort_keys || !sort_keys_at.is_empty() {
+            Some(KeyOrder::Lexical)
         } else {
-            write!(self.writer, " ")?;
-        }
-        self.format_value_content(value)?;
-        Ok(())
-    }
-
-    fn format_value_content(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
-        match value.kind() {
-            nojson::JsonValueKind::Null
-            | nojson::JsonValueKind::Boolean
-            | nojson::JsonValueKind::Integer
-            | nojson::JsonValueKind::Float
-            | nojson::JsonValueKind::String => write!(self.writer, "{}", value.as_raw_str())?,
-            nojson::JsonValueKind::Array => self.format_array(value)?,
-            nojson::JsonValueKind::Object => self.format_object(value)?,
-        }
-        self.text_position = value.position() + value.as_raw_str().len();
-        Ok(())
-    }
-
-    fn has_trailing_comma(&self, close_position: usize) -> bool {
-        let Some(mut position) = self.text[self.text_position..close_position].find(',') else {
-            return false;
-        };
-        position += self.text_position;
-        while self
-            .comment_ranges
-            .range(..position)
-            .next_back()
-            .is_some_and(|(_, &comment_end)| position < comment_end)
-        {
-            position += 1;
-            let Some(offset) = self.text[position..close_position].find(',') else {
-                return false;
-            };
-            position += offset;
-        }
-        true
-    }
-
-    fn format_symbol(&mut self, ch: char) -> std::fmt::Result {
-        let mut position =
-            self.text_position + self.text[self.text_position..].find(ch).expect("bug") + 1;
-        while self
-            .comment_ranges
-            .range(..position)
-            .next_back()
-            .is_some_and(|(_, &end)| position < end)
-        {
-            position += self.text[position..].find(ch).expect("bug") + 1;
-        }
-
-        if (self.multiline_mode && matches!(ch, ']' | '}')) || self.contains_comment(position) {
-            self.format_comments(position)?;
-            if matches!(ch, ']' | '}') {
-                self.text_position = position - 1;
-            }
-            self.indent(position)?;
-        }
-
-        write!(self.writer, "{ch}")?;
-        self.text_position = position;
-        Ok(())
-    }
-
-    fn contains_comment(&self, position: usize) -> bool {
-        self.comment_ranges.range(..position).next().is_some()
-    }
-
-    fn format_comments(&mut self, position: usize) -> std::fmt::Result {
-        self.format_trailing_comment(position)?;
-        self.format_leading_comment(position)?;
-        Ok(())
-    }
-
-    fn format_leading_comment(&mut self, position: usize) -> std::fmt::Result {
-        loop {
-            let Some((comment_start, comment_end)) = self
-                .comment_ranges
-                .range(..position)
-                .next()
-                .map(|x| (*x.0, *x.1))
-            else {
-                return Ok(());
-            };
-
-            self.indent(comment_start)?;
-            self.text_position = comment_start;
-            let comment = &self.text[comment_start..comment_end];
-            if comment.starts_with("//") {
-                write!(self.writer, "{}", comment.trim_end())?;
-            } else {
-                let after_indent = self.level * INDENT_SIZE;
-                let before_indent = self.text[..comment_start]
-                    .lines()
-                    .next_back()
-                    .expect("bug")
-                    .len();
-                for (i, mut line) in comment.lines().enumerate() {
-                    if i == 0 {
-                        write!(self.writer, "{}", line.trim())?;
-                    } else if let Some(delta) = after_indent.checked_sub(before_indent) {
-                        write!(
-                            self.writer,
-                            "\n{:width$}{}",
-                            "",
-                            line.trim_end(),
-                            width = delta
-                        )?;
-                    } else {
-                        let delta = before_indent - after_indent;
-                        for _ in 0..delta {
-                            if let Some(l) = line.strip_prefix(' ') {
-                                line = l;
-                            } else {
-                                break;
-                            };
-                        }
-                        write!(self.writer, "\n{}", line.trim_end())?;
-                    }
-                }
-            }
-            self.comment_ranges.remove(&comment_start);
-            self.text_position = comment_end;
+            None
+        },
+        sort_keys_at: (!sort_keys_at.is_empty()).then_some(sort_keys_at),
+        sort_keys_keep_comments_fixed,
+        allow_hash_comments,
+        allow_comment_only,
+        trailing_content,
+        comments_to_fields,
+        normalize_escapes,
+        normalize_numbers,
+        numbers_as_strings,
+        empty_to_null,
+        array_rows_threshold,
+        object_rows_threshold,
+        hug_closing_brackets,
+        closing_bracket_indent,
+        preview_array_limit,
+        append_stats,
+        changed_lines,
+    };
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+
+    if parse_only_report {
+        let diagnostics = jcfmt::parse_diagnostics(&input, &options);
+        println!("{}", nojson::Json(&diagnostics));
+        if !diagnostics.is_empty() {
+            std::process::exit(1);
         }
+        return Ok(());
     }
 
-    fn format_trailing_comment(&mut self, next_position: usize) -> std::fmt::Result {
-        if self.text_position == 0 {
-            return Ok(());
-        };
-        loop {
-            let Some((comment_start, comment_end)) = self
-                .comment_ranges
-                .range(self.text_position..next_position)
-                .next()
-                .map(|x| (*x.0, *x.1))
-            else {
-                return Ok(());
-            };
-            if self.text[self.text_position..comment_end].contains('\n') {
-                return Ok(());
-            }
-
-            let comment = self.text[comment_start..comment_end].trim_end();
-            write!(self.writer, " {comment}")?;
-            self.comment_ranges.remove(&comment_start);
-            self.text_position = comment_end;
-        }
+    let text = std::str::from_utf8(&input).map_err(FormatError::InvalidUtf8)?;
+    let result = jcfmt::format_detailed(text, &options)?;
+    write_warnings(&mut std::io::stderr(), &result.warnings, quiet)?;
+    if emit_hash {
+        eprintln!("sha256: {}", jcfmt::canonical_hash(text, &options)?);
     }
 
-    fn format_array(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
-        self.format_symbol('[')?;
-        self.level += 1;
-
-        let old_multiline_mode = self.multiline_mode;
-        self.multiline_mode = self.is_newline_needed(value);
-        for (i, element) in value.to_array().expect("bug").enumerate() {
-            if i > 0 {
-                self.format_symbol(',')?;
-                if !self.multiline_mode {
-                    write!(self.writer, " ")?;
-                }
-            }
-            self.format_value(element)?;
-        }
-        let close_position = value.position() + value.as_raw_str().len();
-        if !self.strip && self.has_trailing_comma(close_position) {
-            self.format_symbol(',')?;
-        }
-        self.format_comments(close_position)?;
-
-        self.level -= 1;
-        self.format_symbol(']')?;
-        self.multiline_mode = old_multiline_mode;
-        Ok(())
+    if let Some(path) = output_file {
+        let output = encode_output(&result.formatted, output_encoding)?;
+        write_output_file(&path, &output, backup)?;
+    } else if number_lines {
+        let output = encode_output(&with_line_numbers(&result.formatted), output_encoding)?;
+        std::io::stdout().write_all(&output)?;
+    } else {
+        let output = encode_output(&result.formatted, output_encoding)?;
+        std::io::stdout().write_all(&output)?;
     }
 
-    fn format_object(&mut self, value: nojson::RawJsonValue<'_, '_>) -> std::fmt::Result {
-        self.format_symbol('{')?;
-        self.level += 1;
-
-        let old_multiline_mode = self.multiline_mode;
-        self.multiline_mode = self.is_newline_needed(value);
-        for (i, (key, value)) in value.to_object().expect("bug").enumerate() {
-            if i > 0 {
-                self.format_symbol(',')?;
-                if !self.multiline_mode {
-                    write!(self.writer, " ")?;
-                }
-            }
-
-            self.format_value(key)?;
-            self.format_symbol(':')?;
-            self.format_member_value(value)?;
-        }
-        let close_position = value.position() + value.as_raw_str().len();
-        if !self.strip && self.has_trailing_comma(close_position) {
-            self.format_symbol(',')?;
-        }
-        self.format_comments(close_position)?;
+    Ok(())
+}
 
-        self.level -= 1;
-        self.format_symbol('}')?;
-        self.multiline_mode = old_multiline_mode;
-        Ok(())
+// Writes `content` to `path`, first copying the existing file (if any) to `<path>.bak`
+// if `backup` is set.
+fn write_output_file(path: &Path, content: &[u8], backup: bool) -> std::io::Result<()> {
+    if backup && path.exists() {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        std::fs::copy(path, backup_path)?;
     }
+    std::fs::write(path, content)
+}
 
-    fn is_newline_needed(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
-        self.is_comment_included(value) || self.is_newline_included(value)
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputEncoding {
+    Utf8,
+    // Without a BOM.
+    Utf16Le,
+    Latin1,
+}
 
-    fn is_comment_included(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
-        let start = value.position();
-        let end = start + value.as_raw_str().len();
-        self.comment_ranges.range(start..end).next().is_some()
+fn encode_output(text: &str, encoding: OutputEncoding) -> Result<Vec<u8>, String> {
+    match encoding {
+        OutputEncoding::Utf8 => Ok(text.as_bytes().to_vec()),
+        OutputEncoding::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+        OutputEncoding::Latin1 => text
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                u8::try_from(c).map_err(|_| {
+                    let line = text.chars().take(i).filter(|&c| c == '\n').count() + 1;
+                    format!("output line {line} has {c:?}, which can't be encoded in Latin-1")
+                })
+            })
+            .collect(),
     }
+}
 
-    fn is_newline_included(&self, value: nojson::RawJsonValue<'_, '_>) -> bool {
-        let start = value.position();
-        let end = start + value.as_raw_str().len();
-        self.text[start..end].contains('\n')
+// Prefixes each line with its right-aligned line number, like `cat -n`.
+fn with_line_numbers(text: &str) -> String {
+    let mut numbered = String::new();
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        numbered.push_str(&format!("{:6}\t{line}", i + 1));
     }
+    numbered
+}
 
-    fn blank_line(&mut self, position: usize) -> std::fmt::Result {
-        let Some(offset) = self.text[self.text_position..position].find('\n') else {
-            return Ok(());
-        };
-        self.text_position += offset + 1;
-
-        let Some(offset) = self.text[self.text_position..position].find('\n') else {
-            return Ok(());
-        };
-        self.text_position += offset + 1;
-
-        writeln!(self.writer)?;
-
-        Ok(())
+fn write_warnings(
+    writer: &mut impl std::io::Write,
+    warnings: &[Warning],
+    quiet: bool,
+) -> std::io::Result<()> {
+    if quiet {
+        return Ok(());
     }
-
-    fn indent(&mut self, position: usize) -> std::fmt::Result {
-        if self.text_position == 0 {
-            return Ok(());
-        }
-        self.blank_line(position)?;
-        write!(
-            self.writer,
-            "\n{:width$}",
-            "",
-            width = self.level * INDENT_SIZE
-        )
+    for warning in warnings {
+        writeln!(writer, "warning: {warning}")?;
     }
+    Ok(())
 }
 
-fn format_json_parse_error(text: &str, error: nojson::JsonParseError) -> String {
-    let (line_num, column_num) = error
-        .get_line_and_column_numbers(text)
-        .unwrap_or((NonZeroUsize::MIN, NonZeroUsize::MIN));
-
-    let line = error.get_line(text).unwrap_or("");
-
-    let prev_line = if line_num.get() == 1 {
-        None
-    } else {
-        text.lines().nth(line_num.get() - 2)
-    };
-
-    let (display_line, display_column) = format_line_around_position(line, column_num.get());
-    let prev_display_line = prev_line.map(|prev| {
-        let (truncated, _) = format_line_around_position(prev, column_num.get());
-        truncated
-    });
-
-    format!(
-        "{error}\n\nINPUT:{}\n{line_num:4} |{display_line}\n     |{:>column$} error",
-        if let Some(prev) = prev_display_line {
-            format!("\n     |{prev}")
-        } else {
-            "".to_owned()
-        },
-        "^",
-        column = display_column
-    )
+fn parse_indent_spec(s: &str) -> Result<Vec<usize>, String> {
+    let columns = s
+        .split(',')
+        .map(|n| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|e| format!("invalid column {n:?}: {e}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if columns.windows(2).any(|w| w[0] > w[1]) {
+        return Err("columns must be in non-decreasing order".to_owned());
+    }
+    Ok(columns)
 }
 
-fn format_line_around_position(line: &str, column_pos: usize) -> (String, usize) {
-    const MAX_ERROR_LINE_CHARS: usize = 80;
-
-    let chars: Vec<char> = line.chars().collect();
-    let max_context = MAX_ERROR_LINE_CHARS / 2;
-
-    let error_pos = column_pos.saturating_sub(1).min(chars.len());
-    let start_pos = error_pos.saturating_sub(max_context);
-    let end_pos = (error_pos + max_context + 1).min(chars.len());
-
-    let mut result = String::new();
-    let mut new_column_pos = error_pos - start_pos + 1;
-
-    if start_pos > 0 {
-        result.push_str("...");
-        new_column_pos += 3;
-    }
-
-    result.push_str(&chars[start_pos..end_pos].iter().collect::<String>());
-
-    if end_pos < chars.len() {
-        result.push_str("...");
+fn parse_empty_values(s: &str) -> Result<EmptyValues, String> {
+    let mut kinds = EmptyValues::default();
+    for kind in s.split(',') {
+        match kind.trim() {
+            "string" => kinds.strings = true,
+            "array" => kinds.arrays = true,
+            "object" => kinds.objects = true,
+            _ => return Err(format!("unknown kind {kind:?}")),
+        }
     }
+    Ok(kinds)
+}
 
-    (result, new_column_pos)
+fn parse_line_ranges(s: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+    s.split(',')
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<NonZeroUsize>()
+                    .map(NonZeroUsize::get)
+                    .map_err(|e| format!("invalid line number {n:?}: {e}"))
+            };
+            Ok(parse(start)?..=parse(end)?)
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn format(text: &str) -> String {
-        let (json, comment_ranges) = nojson::RawJson::parse_jsonc(text).expect("bug");
-        let mut buf = String::new();
-        let mut formatter = Formatter::new(&text, comment_ranges, &mut buf, false);
-        formatter.format(json.value()).expect("bug");
-        buf
+    #[test]
+    fn indent_spec() {
+        assert_eq!(parse_indent_spec("4,6,10").expect("bug"), [4, 6, 10]);
+        assert!(parse_indent_spec("2,1").is_err());
+        assert!(parse_indent_spec("2,x").is_err());
     }
 
     #[test]
-    fn literals() {
-        assert_eq!(format(" null  "), "null\n");
-        assert_eq!(format(" \t\n false\n\n  "), "false\n");
-        assert_eq!(format(" 1\n "), "1\n");
-        assert_eq!(format(" \n\"foo\" "), "\"foo\"\n");
+    fn empty_values() {
+        let kinds = parse_empty_values("string,object").expect("bug");
+        assert!(kinds.strings && !kinds.arrays && kinds.objects);
+        assert!(parse_empty_values("number").is_err());
     }
 
     #[test]
-    fn empty_containers() {
-        assert_eq!(format("[]"), "[]\n");
-        assert_eq!(format("{}"), "{}\n");
-        assert_eq!(format(" [ ] "), "[]\n");
-        assert_eq!(format(" { } "), "{}\n");
+    fn line_ranges() {
+        assert_eq!(parse_line_ranges("2,4-6").expect("bug"), [2..=2, 4..=6]);
+        assert!(parse_line_ranges("0-3").is_err());
+        assert!(parse_line_ranges("foo").is_err());
     }
 
     #[test]
-    fn arrays() {
-        assert_eq!(format("[1, 2, 3]"), "[1, 2, 3]\n");
-        assert_eq!(format("[1,2,3]"), "[1, 2, 3]\n");
-        assert_eq!(format("[ 1 , 2 , 3 ]"), "[1, 2, 3]\n");
+    fn quiet() {
+        let text = r#"{"a": 1, "a": 2}"#;
+        let result = jcfmt::format_detailed(text, &FormatOptions::default()).expect("bug");
 
-        // Multiline arrays
-        assert_eq!(format("[\n  1,\n  2,\n  3\n]"), "[\n  1,\n  2,\n  3\n]\n");
+        let mut stderr = Vec::new();
+        write_warnings(&mut stderr, &result.warnings, false).expect("bug");
+        assert_eq!(stderr, b"warning: duplicate key \"a\" at line 1\n");
 
-        // Nested arrays
-        assert_eq!(format("[[1, 2], [3, 4]]"), "[[1, 2], [3, 4]]\n");
-        assert_eq!(
-            format("[\n  [1, 2],\n  [3, 4]\n]"),
-            "[\n  [1, 2],\n  [3, 4]\n]\n"
-        );
+        let mut stderr = Vec::new();
+        write_warnings(&mut stderr, &result.warnings, true).expect("bug");
+        assert!(stderr.is_empty());
     }
 
     #[test]
-    fn objects() {
-        assert_eq!(format("{\"a\": 1}"), "{\"a\": 1}\n");
-        assert_eq!(format("{\"a\":1}"), "{\"a\": 1}\n");
-        assert_eq!(format("{ \"a\" : 1 }"), "{\"a\": 1}\n");
-
-        // Multiple properties
-        assert_eq!(format("{\"a\": 1, \"b\": 2}"), "{\"a\": 1, \"b\": 2}\n");
-
-        // Multiline objects
+    fn backup() {
+        let dir = std::env::temp_dir().join(format!("jcfmt-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("bug");
+        let path = dir.join("a.json");
+        std::fs::write(&path, "{\"a\":1}").expect("bug");
+
+        let formatted = jcfmt::format_text("{\"a\":1}", &FormatOptions::default()).expect("bug");
+        write_output_file(&path, formatted.as_bytes(), true).expect("bug");
+        assert_eq!(std::fs::read_to_string(&path).expect("bug"), "{\"a\": 1}\n");
         assert_eq!(
-            format("{\n  \"a\": 1,\n  \"b\": 2\n}"),
-            "{\n  \"a\": 1,\n  \"b\": 2\n}\n"
+            std::fs::read_to_string(dir.join("a.json.bak")).expect("bug"),
+            "{\"a\":1}"
         );
 
-        // Nested objects
-        assert_eq!(
-            format("{\"outer\": {\"inner\": 42}}"),
-            "{\"outer\": {\"inner\": 42}}\n"
-        );
+        // Without an existing file, there is nothing to back up.
+        let path = dir.join("b.json");
+        write_output_file(&path, formatted.as_bytes(), true).expect("bug");
+        assert!(!dir.join("b.json.bak").exists());
+
+        std::fs::remove_dir_all(dir).expect("bug");
     }
 
     #[test]
-    fn mixed_structures() {
+    fn output_encoding() {
+        let formatted = jcfmt::format_text("[\"é\"]", &FormatOptions::default()).expect("bug");
         assert_eq!(
-            format("{\"array\": [1, 2, 3], \"object\": {\"nested\": true}}"),
-            "{\"array\": [1, 2, 3], \"object\": {\"nested\": true}}\n"
+            encode_output(&formatted, OutputEncoding::Utf16Le).expect("bug"),
+            b"[\0\"\0\xe9\0\"\0]\0\n\0"
         );
-
         assert_eq!(
-            format("[{\"a\": 1}, {\"b\": 2}]"),
-            "[{\"a\": 1}, {\"b\": 2}]\n"
+            encode_output(&formatted, OutputEncoding::Latin1).expect("bug"),
+            b"[\"\xe9\"]\n"
+        );
+        assert_eq!(
+            encode_output(&formatted, OutputEncoding::Utf8).expect("bug"),
+            "[\"é\"]\n".as_bytes()
+        );
+        assert_eq!(
+            encode_output("1\n\"€\"", OutputEncoding::Latin1),
+            Err("output line 2 has '€', which can't be encoded in Latin-1".to_owned())
         );
     }
 
     #[test]
-    fn indentation() {
-        let input = r#"{
-"level1": {
-"level2": {
-"level3": "value"
-}
-}
-}"#;
-        let expected = r#"{
-  "level1": {
-    "level2": {
-      "level3": "value"
-    }
-  }
-}
-"#;
-        assert_eq!(format(input), expected);
-    }
-
-    #[test]
-    fn comments_single_line() {
-        let input = r#"{
-  "key": "value" // This is a comment
-}"#;
-        let expected = r#"{
-  "key": "value" // This is a comment
-}
-"#;
-        assert_eq!(format(input), expected);
-    }
-
-    #[test]
-    fn comments_multi_line() {
-        let input = r#"{
-  /* This is a
-     multi-line comment */
-  "key": "value"
-}"#;
-        let expected = r#"{
-  /* This is a
-     multi-line comment */
-  "key": "value"
-}
-"#;
-        assert_eq!(format(input), expected);
-    }
-
-    #[test]
-    fn comments_leading() {
-        let input = r#"// Leading comment
-{
-  "key": "value"
-}"#;
-        let expected = r#"// Leading comment
-{
-  "key": "value"
-}
-"#;
-        assert_eq!(format(input), expected);
-    }
-
-    #[test]
-    fn comments_mixed() {
-        let input = r#"{
-  // Comment before key
-  "key1": "value1", // Trailing comment
-  /* Block comment */
-  "key2": "value2"
-}"#;
-        let expected = r#"{
-  // Comment before key
-  "key1": "value1", // Trailing comment
-  /* Block comment */
-  "key2": "value2"
-}
-"#;
-        assert_eq!(format(input), expected);
-    }
-
-    #[test]
-    fn various_json_types() {
-        let input = r#"{
-  "null": null,
-  "boolean_true": true,
-  "boolean_false": false,
-  "integer": 42,
-  "float": 3.14,
-  "string": "hello world",
-  "empty_string": "",
-  "array": [],
-  "object": {}
-}"#;
-        let expected = r#"{
-  "null": null,
-  "boolean_true": true,
-  "boolean_false": false,
-  "integer": 42,
-  "float": 3.14,
-  "string": "hello world",
-  "empty_string": "",
-  "array": [],
-  "object": {}
-}
-"#;
-        assert_eq!(format(input), expected);
-    }
-
-    #[test]
-    fn whitespace_normalization() {
-        // Test excessive whitespace removal
-        let input = r#"{
-
-
-  "key"   :    "value"   ,
-
-
-  "another"  :   42
-
-
-}"#;
-        let expected = r#"{
-
-  "key": "value",
-
-  "another": 42
-}
-"#;
-        assert_eq!(format(input), expected);
-    }
-
-    #[test]
-    fn trailing_commas() {
-        // Test trailing comma in array
-        let input = r#"[
-  1,
-  2,
-  3,
-]"#;
-        let expected = r#"[
-  1,
-  2,
-  3,
-]
-"#;
-        assert_eq!(format(input), expected);
-
-        let input = r#"[1,2,3,]"#;
-        let expected = r#"[1, 2, 3,]
-"#;
-        assert_eq!(format(input), expected);
-        // Test trailing comma in object
-        let input = r#"{
-  "key1": "value1",
-  "key2": "value2",
-}"#;
-        let expected = r#"{
-  "key1": "value1",
-  "key2": "value2",
-}
-"#;
-        assert_eq!(format(input), expected);
-
-        // Test trailing comma with comments
-        let input = r#"{
-  "key1": "value1", // Comment after value
-  "key2": "value2", // Another comment
-  // Final comment before trailing comma
-}"#;
-        let expected = r#"{
-  "key1": "value1", // Comment after value
-  "key2": "value2", // Another comment
-  // Final comment before trailing comma
-}
-"#;
-        assert_eq!(format(input), expected);
-
-        // Test nested structures with trailing commas
-        let input = r#"{
-  "array": [
-    1,
-    2,
-  ],
-  "object": {
-    "nested": true,
-  },
-}"#;
-        let expected = r#"{
-  "array": [
-    1,
-    2,
-  ],
-  "object": {
-    "nested": true,
-  },
-}
-"#;
-        assert_eq!(format(input), expected);
+    fn number_lines() {
+        let formatted = jcfmt::format_text(
+            r#"{"a": 1,
+"b": 2}"#,
+            &FormatOptions::default(),
+        )
+        .expect("bug");
+        assert_eq!(
+            with_line_numbers(&formatted),
+            "     1\t{\n     2\t  \"a\": 1,\n     3\t  \"b\": 2\n     4\t}\n"
+        );
     }
 }